@@ -1,184 +1,498 @@
-use std::convert::TryFrom;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::net::{UdpSocket, SocketAddr};
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::{Instant, Duration};
-use crate::{repeater, server};
-use crate::protocol::{
-    MessageHeader,
-    Command,
-};
+use mio::{Poll, Registry, Token, Waker};
+use crate::{repeater, ClientConfig};
+use crate::protocol::Message;
 
-use log::{info, warn, error, debug, trace};
+use log::{warn, debug, trace};
+
+pub mod circuit;
+pub mod channel;
+pub mod subscription;
+mod event_loop;
+pub use circuit::{Circuit, CircuitEvent};
+pub use channel::{Channel, ChannelEvent, ConnectionState};
+pub use subscription::{Monitor, Subscription};
+#[cfg(feature = "tokio")]
+pub mod async_client;
+#[cfg(feature = "tokio")]
+pub use async_client::{AsyncChannel, AsyncClient, AsyncMonitor};
 
 const UPDATE_PERIOD: f64 = 0.5;
 
+/// Delay before the first search retry. Each following retry doubles the delay.
+const SEARCH_MIN_PERIOD: f64 = 0.032;
+/// Longest delay between two search retries.
+const SEARCH_MAX_PERIOD: f64 = 5.0;
+/// Largest UDP datagram the client expects to receive.
+const MAX_UDP_SIZE: usize = 1472;
+
 #[derive(Debug)]
 pub enum Error {
     IoError(String),
     RegistrationError(String),
+    TimeoutError(String),
+    ChannelError(String),
+    ProtocolError(String),
+    /// The server denied read or write access to a channel.
+    AccessError(String),
+    /// A server reported a failed request with an ECA status code and its message text.
+    EcaError(u32, String),
 }
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
         Error::IoError(format!("{:?}",e))
     }
 }
+impl From<crate::protocol::Error> for Error {
+    fn from(e: crate::protocol::Error) -> Self {
+        Error::ProtocolError(format!("{:?}",e))
+    }
+}
+impl Error {
+    /// Builds the error for a failed ECA status code.
+    pub fn from_eca(status: u32) -> Self {
+        Error::EcaError(status, crate::protocol::eca::message(status).into())
+    }
+}
+
+
+/// Location of the server hosting a channel, as returned by a successful search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchResult {
+    /// TCP address of the server.
+    pub address: SocketAddr,
+    /// CA minor protocol version spoken by the server.
+    pub minor_version: u16,
+}
 
+/// Open virtual circuits keyed by server address and priority.
+type CircuitMap = HashMap<(SocketAddr, u16), Arc<Circuit>>;
+
+/// Unexpected change in a server's beacons, suggesting that searches should be repeated promptly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BeaconAnomaly {
+    /// First beacon from a server.
+    NewServer,
+    /// The beacon ID went backwards, so the server was restarted.
+    Restarted,
+    /// Beacons were missed, so the server may have been unreachable.
+    IdGap,
+    /// The beacon period changed sharply, as it does after a restart or a network outage.
+    PeriodChanged,
+}
 
+/// Beacon history of one server, keyed by its TCP address.
 struct ServerRecord {
-    tcp_address: SocketAddr,
+    minor_version: u16,
     last_beacon_id: u32,
     last_beacon_timestamp: Instant,
+    /// Interval between the last two consecutive beacons.
+    beacon_period: Option<Duration>,
 }
+impl ServerRecord {
+    fn new(minor_version: u16, beacon_id: u32, now: Instant) -> Self {
+        Self { minor_version, last_beacon_id: beacon_id, last_beacon_timestamp: now, beacon_period: None }
+    }
 
-pub struct Client {
-    repeater_socket: Arc<Mutex<UdpSocket>>,
-    registered: Arc<Mutex<bool>>,
-    server_list: Arc<Mutex<Vec<ServerRecord>>>,
-    process_stopper: Option<Sender<bool>>,
-    update_stopper:  Option<Sender<bool>>,
-}
+    /// Records a beacon received at `now`, returning the anomaly it reveals, if any.
+    fn update(&mut self, beacon_id: u32, now: Instant) -> Option<BeaconAnomaly> {
+        let step = beacon_id.wrapping_sub(self.last_beacon_id);
+        if step == 0 {
+            // Duplicate received through another interface
+            return None;
+        }
 
-impl Client {
-    pub fn new() -> Result<Self, Error> {
-        repeater::init();
-        //std::thread::sleep(std::time::Duration::from_millis(10));
-        let mut instance = Self {
-            repeater_socket: Arc::new(Mutex::new(UdpSocket::bind("127.0.0.1:0")?)),
-            registered: Arc::new(Mutex::new(false)),
-            server_list: Arc::new(Mutex::new(vec!())),
-            process_stopper: None,
-            update_stopper: None,
+        let interval = now - self.last_beacon_timestamp;
+        let anomaly = if step > u32::MAX / 2 {
+            Some(BeaconAnomaly::Restarted)
+        } else if step > 1 {
+            Some(BeaconAnomaly::IdGap)
+        } else {
+            match self.beacon_period {
+                Some(period) if interval < period / 3 || interval > period * 3 => Some(BeaconAnomaly::PeriodChanged),
+                _ => None,
+            }
         };
 
-        instance.register()?;
+        self.beacon_period = if anomaly.is_none() || anomaly == Some(BeaconAnomaly::PeriodChanged) { Some(interval) } else { None };
+        self.last_beacon_id = beacon_id;
+        self.last_beacon_timestamp = now;
+        anomaly
+    }
+}
 
-        // Start processing threads
-        instance.start_processing_packets();
-        instance.start_processing_update();
+/// Server discovered through its beacons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// TCP address of the server.
+    pub address: SocketAddr,
+    /// CA minor protocol version announced in the beacons.
+    pub minor_version: u16,
+    /// ID of the last beacon received.
+    pub beacon_id: u32,
+    /// Time the last beacon was received.
+    pub last_beacon: Instant,
+    /// Interval between the last two consecutive beacons, once known.
+    pub beacon_period: Option<Duration>,
+}
 
-        Ok(instance)
-    }
+/// Change in the set of known servers, derived from beacons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A beacon was received from a server that was not known.
+    ServerAppeared(SocketAddr),
+    /// The beacon ID of a known server went backwards.
+    ServerRestarted(SocketAddr),
+    /// No beacon was received from a known server for twice the configured EPICS_CA_BEACON_PERIOD.
+    ServerLost(SocketAddr),
+}
 
-    /// Returns true if the client has registered with the repeater and received a confirmation message.
-    pub fn is_registered(&self) -> bool {
-        *self.registered.lock().unwrap()
-    }
-
-    /// Registers client with the local repeater and waits for confirmation
-    fn register(&mut self) -> Result<(), Error> {
-        let registration_header = MessageHeader {
-            command: Command::CA_REPEATER_REGISTER.into(),
-            payload_size: 0,
-            data_type: 0,
-            data_count: 0,
-            parameter_1: 0,
-            parameter_2: crate::LOCALHOST_U32,  
+/// Servers known from their beacons, and the listeners notified of changes.
+#[derive(Default)]
+struct ServerList {
+    records: HashMap<SocketAddr, ServerRecord>,
+    listeners: Vec<Sender<ServerEvent>>,
+}
+impl ServerList {
+    /// Records a beacon from `address`, returning the anomaly it reveals, if any.
+    fn beacon(&mut self, address: SocketAddr, minor_version: u16, beacon_id: u32, now: Instant) -> Option<BeaconAnomaly> {
+        let anomaly = match self.records.entry(address) {
+            Entry::Occupied(mut record) => {
+                record.get_mut().minor_version = minor_version;
+                record.get_mut().update(beacon_id, now)
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(ServerRecord::new(minor_version, beacon_id, now));
+                Some(BeaconAnomaly::NewServer)
+            },
         };
+        match anomaly {
+            Some(BeaconAnomaly::NewServer) => self.notify(ServerEvent::ServerAppeared(address)),
+            Some(BeaconAnomaly::Restarted) => self.notify(ServerEvent::ServerRestarted(address)),
+            _ => (),
+        }
+        anomaly
+    }
 
-        // Send registration message
-        if let Err(e) = self.repeater_socket.lock().unwrap().send_to(
-            &registration_header.as_bytes(), 
-            SocketAddr::from_str(format!("127.0.0.1:{}", crate::CA_REPEATER_PORT).as_str()).unwrap()
-        ) {
-            return Err(Error::IoError(format!("Could not send registration packet: {:?}", e)))
+    /// Forgets servers whose last beacon is older than `timeout`.
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        let lost: Vec<SocketAddr> = self.records.iter()
+            .filter(|(_, record)| now - record.last_beacon_timestamp >= timeout)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in lost {
+            self.records.remove(&address);
+            self.notify(ServerEvent::ServerLost(address));
         }
-        debug!("Registration message sent");
-        Ok(())
-    }
-
-    /// Spawns a new thread that handles incoming datagrams.
-    pub fn start_processing_packets(&mut self) {
-        let (tx, rx) = channel::<bool>();
-
-        let socket = self.repeater_socket.clone();
-        let registered = self.registered.clone();
-
-        std::thread::spawn(move || {
-            let mut packet_buf = [0u8; crate::protocol::HEADER_SIZE];
-
-            // Loop over incoming UDP packets
-            while let Ok((amt, src)) = socket.lock().unwrap().recv_from(&mut packet_buf) {
-                // Parse received packet into a message header
-                if let Ok(header) = MessageHeader::from_bytes(&packet_buf) {
-                    match Command::try_from(header.command) {
-                        Ok(Command::CA_REPEATER_CONFIRM) => {
-                            // Store repeater confirmation
-                            debug!("Received registration confirmation from repeater");
-                            *registered.lock().unwrap() = true;
-                        },
-                        Ok(Command::CA_PROTO_RSRV_IS_UP) => {
-                            // Update server list
-                            trace!("Received server beacon");
-                            todo!()
-                        }
-                        Err(e) => {
-                            error!("Error receiving UDP packet: {:?}", e);
-                            continue;
-                        }
-                        _ => {
-                            warn!("Client received unsupported message command");
-                            continue;
-                        }
-                    }
-                }
-
-                // Check for stop signal
-                if let Ok(stop) = rx.try_recv() {
-                    if stop { break; }
-                }
-            }0
+    }
+
+    fn servers(&self) -> Vec<ServerInfo> {
+        self.records.iter().map(|(address, record)| ServerInfo {
+            address: *address,
+            minor_version: record.minor_version,
+            beacon_id: record.last_beacon_id,
+            last_beacon: record.last_beacon_timestamp,
+            beacon_period: record.beacon_period,
+        }).collect()
+    }
+
+    /// Sends `event` to every listener, dropping the ones that have gone away.
+    fn notify(&mut self, event: ServerEvent) {
+        debug!("{:?}", event);
+        self.listeners.retain(|listener| listener.send(event).is_ok());
+    }
+}
+
+/// Callback receiving the reply to a pending search.
+pub(crate) type SearchHandler = Box<dyn FnOnce(SearchResult) + Send>;
+
+/// Search request repeated by the event loop until a server replies or the search is ended.
+struct PendingSearch {
+    handler: SearchHandler,
+    /// Encoded CA_PROTO_SEARCH message.
+    request: Vec<u8>,
+    /// Delay before the retry following the next request.
+    period: Duration,
+    next_send: Instant,
+}
+
+/// State shared between the client, its channels and its event loop.
+pub(crate) struct Shared {
+    config: ClientConfig,
+    search_addresses: Mutex<Vec<SocketAddr>>,
+    pending_searches: Mutex<HashMap<u32, PendingSearch>>,
+    next_id: AtomicU32,
+    circuits: Mutex<CircuitMap>,
+    /// Circuits whose sockets are registered with the event loop, by token.
+    tokens: Mutex<HashMap<Token, Arc<Circuit>>>,
+    server_list: Mutex<ServerList>,
+    registered: AtomicBool,
+    registry: Registry,
+    waker: Waker,
+    stopped: AtomicBool,
+}
+impl Shared {
+    /// Allocates an ID that is unique across channels, requests and subscriptions of this client.
+    pub(crate) fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Starts searching for `name`, returning the search's cid. `handler` is called on the event loop thread with the first reply.
+    pub(crate) fn start_search(&self, name: &str, handler: SearchHandler) -> u32 {
+        let cid = self.next_id();
+        let request = Message::Search { reply: false, minor_version: crate::MINOR_PROTOCOL_VERSION, cid, name: name.into() }.as_bytes();
+        self.pending_searches.lock().unwrap().insert(cid, PendingSearch {
+            handler,
+            request,
+            period: Duration::from_secs_f64(SEARCH_MIN_PERIOD),
+            next_send: Instant::now(),
         });
+        trace!("Searching for {} (cid {})", name, cid);
+        self.wake();
+        cid
+    }
+
+    /// Forgets a pending search.
+    pub(crate) fn end_search(&self, cid: u32) {
+        self.pending_searches.lock().unwrap().remove(&cid);
+    }
+
+    /// Searches for the server hosting `name`, waiting up to `timeout` for a reply.
+    pub(crate) fn search_timeout(&self, name: &str, timeout: Duration) -> Result<SearchResult, Error> {
+        let (tx, rx) = channel::<SearchResult>();
+        let cid = self.start_search(name, Box::new(move |result| {
+            let _ = tx.send(result);
+        }));
+
+        let result = rx.recv_timeout(timeout)
+            .map_err(|_| Error::TimeoutError(format!("No server replied to search for {}", name)));
+        self.end_search(cid);
+        result
+    }
 
-        self.process_stopper = Some(tx);
+    /// Makes every pending search send its next request right away and restart its backoff.
+    fn reset_searches(&self) {
+        let now = Instant::now();
+        for search in self.pending_searches.lock().unwrap().values_mut() {
+            search.period = Duration::from_secs_f64(SEARCH_MIN_PERIOD);
+            search.next_send = now;
+        }
     }
 
-    /// Sends a stop message via an mpsc channel to the processing thread
-    pub fn stop_processing_packets(&mut self) {
-        if let Some(sender) = &self.process_stopper {
-            if let Err(e) = sender.send(true) {
-                error!("Could not stop packet processing thread: {:?}", e)
+    /// Returns the open virtual circuit to `address` at `priority`, connecting a new one if there is none.
+    pub(crate) fn circuit(&self, address: SocketAddr, priority: u16) -> Result<Arc<Circuit>, Error> {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get(&(address, priority)) {
+            if !circuit.is_closed() {
+                return Ok(circuit.clone());
             }
         }
+
+        // Hold the token map while registering so the event loop cannot miss the circuit's first events
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = Token(self.next_id() as usize);
+        let circuit = Circuit::connect(address, priority, &self.config, &self.registry, token)?;
+        tokens.insert(token, circuit.clone());
+        circuits.insert((address, priority), circuit.clone());
+        Ok(circuit)
     }
 
-    /// Spawns a new thread that handles periodic tasks like checking server timeouts.
-    pub fn start_processing_update(&mut self) {
-        let (tx, rx) = channel::<bool>();
-
-        let servers = self.server_list.clone();
-        
-        std::thread::spawn(move || {
-            loop {
-                // Remove expired server records
-                let mut lock = servers.lock().unwrap();
-    
-                lock.retain(|server_record| {
-                    Instant::now() - server_record.last_beacon_timestamp < Duration::from_secs_f64(crate::CA_SERVER_BEACON_MAX_PERIOD*2.0)
-                });
-    
-    
-                // Check for stop signal
-                if let Ok(stop) = rx.try_recv() {
-                    if stop { break; }
-                }
-
-                // Sleep for update period
-                std::thread::sleep(Duration::from_secs_f64(UPDATE_PERIOD))
+    /// Interrupts the event loop's wait so it picks up new work.
+    fn wake(&self) {
+        if self.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = self.waker.wake() {
+            warn!("Could not wake event loop: {:?}", e);
+        }
+    }
 
-            }
+    /// Asks the event loop to close every circuit and exit.
+    fn stop(&self) {
+        // Set the flag first so the woken loop cannot miss it and wait for its next tick
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            warn!("Could not wake event loop: {:?}", e);
+        }
+    }
+}
+
+/// Channel Access client.
+///
+/// Every socket of the client is served by a single event loop thread, which also runs subscription, event and
+/// search callbacks. Dropping the client stops the loop at once and closes its circuits.
+pub struct Client {
+    shared: Arc<Shared>,
+}
+
+impl Client {
+    /// Creates a client configured from the EPICS_CA_* environment variables.
+    pub fn new() -> Result<Self, Error> {
+        Self::with_config(ClientConfig::from_env())
+    }
+
+    /// Creates a client with an explicit configuration.
+    pub fn with_config(config: ClientConfig) -> Result<Self, Error> {
+        repeater::init(config.repeater_port);
+        let search_socket = UdpSocket::bind("0.0.0.0:0")?;
+        search_socket.set_broadcast(true)?;
+        let repeater_socket = UdpSocket::bind("127.0.0.1:0")?;
+
+        let poll = Poll::new()?;
+        let shared = Arc::new(Shared {
+            search_addresses: Mutex::new(config.search_addresses()),
+            config,
+            pending_searches: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            circuits: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+            server_list: Mutex::new(ServerList::default()),
+            registered: AtomicBool::new(false),
+            registry: poll.registry().try_clone()?,
+            waker: Waker::new(poll.registry(), event_loop::WAKER)?,
+            stopped: AtomicBool::new(false),
         });
 
-        self.update_stopper = Some(tx);
+        event_loop::EventLoop::new(poll, shared.clone(), repeater_socket, search_socket)?.spawn();
+
+        Ok(Self { shared })
     }
 
-    /// Sends a stop message via an mpsc channel to the update thread
-    pub fn stop_processing_update(&mut self) {
-        if let Some(sender) = &self.update_stopper {
-            if let Err(e) = sender.send(true) {
-                error!("Could not update thread: {:?}", e)
-            }
-        }
+    /// Returns the configuration the client was created with.
+    pub fn config(&self) -> &ClientConfig {
+        &self.shared.config
     }
-}
\ No newline at end of file
+
+    /// Sets the broadcast and unicast addresses search requests are sent to.
+    /// Replaces the addresses derived from the configuration.
+    pub fn set_search_addresses(&self, addresses: Vec<SocketAddr>) {
+        *self.shared.search_addresses.lock().unwrap() = addresses;
+    }
+
+    /// Returns the addresses search requests are sent to.
+    pub fn search_addresses(&self) -> Vec<SocketAddr> {
+        self.shared.search_addresses.lock().unwrap().clone()
+    }
+
+    /// Searches for the server hosting `name`, waiting up to the configured search timeout for a reply.
+    pub fn search(&self, name: &str) -> Result<SearchResult, Error> {
+        self.search_timeout(name, self.shared.config.search_timeout)
+    }
+
+    /// Searches for the server hosting `name`, waiting up to `timeout` for a reply.
+    ///
+    /// Requests are repeated with an exponentially increasing delay until a server answers or the timeout expires.
+    pub fn search_timeout(&self, name: &str, timeout: Duration) -> Result<SearchResult, Error> {
+        self.shared.search_timeout(name, timeout)
+    }
+
+    /// Creates a channel to the process variable `name` at the default priority.
+    ///
+    /// The channel is returned immediately and connects in the background; use [`Channel::wait_connected`] to wait for it.
+    pub fn create_channel(&self, name: &str) -> Channel {
+        Channel::create(self.shared.clone(), name, 0)
+    }
+
+    /// Creates a channel to the process variable `name` on a circuit of the given priority (0 to 99).
+    pub fn create_channel_with_priority(&self, name: &str, priority: u16) -> Channel {
+        Channel::create(self.shared.clone(), name, priority.min(channel::MAX_PRIORITY))
+    }
+
+    /// Returns true if the client has registered with the repeater and received a confirmation message.
+    pub fn is_registered(&self) -> bool {
+        self.shared.registered.load(Ordering::SeqCst)
+    }
+
+    /// Returns the open virtual circuit to `address` at `priority`, connecting a new one if there is none.
+    ///
+    /// A new circuit connects in the background; messages sent meanwhile are queued.
+    pub fn circuit(&self, address: SocketAddr, priority: u16) -> Result<Arc<Circuit>, Error> {
+        self.shared.circuit(address, priority)
+    }
+
+    /// Asks the servers of every open circuit to stop sending subscription updates until resumed.
+    pub fn pause_events(&self) -> Result<(), Error> {
+        self.open_circuits().iter().try_for_each(|circuit| circuit.pause_events())
+    }
+
+    /// Asks the servers of every open circuit to resume subscription updates.
+    pub fn resume_events(&self) -> Result<(), Error> {
+        self.open_circuits().iter().try_for_each(|circuit| circuit.resume_events())
+    }
+
+    /// Returns the servers currently known from their beacons.
+    pub fn servers(&self) -> Vec<ServerInfo> {
+        self.shared.server_list.lock().unwrap().servers()
+    }
+
+    /// Returns a receiver for the server discovery events seen from now on.
+    pub fn server_events(&self) -> Receiver<ServerEvent> {
+        let (tx, rx) = channel();
+        self.shared.server_list.lock().unwrap().listeners.push(tx);
+        rx
+    }
+
+    fn open_circuits(&self) -> Vec<Arc<Circuit>> {
+        self.shared.circuits.lock().unwrap().values().filter(|c| !c.is_closed()).cloned().collect()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beacon_anomalies() {
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+
+        let mut record = ServerRecord::new(11, 10, at(0.0));
+        assert_eq!(record.update(11, at(15.0)), None);
+        assert_eq!(record.update(11, at(15.1)), None);
+        assert_eq!(record.update(12, at(30.0)), None);
+        assert_eq!(record.update(13, at(31.0)), Some(BeaconAnomaly::PeriodChanged));
+        assert_eq!(record.update(15, at(46.0)), Some(BeaconAnomaly::IdGap));
+        assert_eq!(record.update(0, at(47.0)), Some(BeaconAnomaly::Restarted));
+        assert_eq!(record.update(1, at(47.02)), None);
+        assert_eq!(record.update(2, at(47.06)), None);
+    }
+
+    #[test]
+    fn server_list_events() {
+        let start = Instant::now();
+        let address: SocketAddr = "10.0.0.1:5064".parse().unwrap();
+        let mut list = ServerList::default();
+        let (tx, rx) = channel();
+        list.listeners.push(tx);
+
+        list.beacon(address, 13, 5, start);
+        list.beacon(address, 13, 6, start + Duration::from_secs(1));
+        list.beacon(address, 13, 0, start + Duration::from_secs(2));
+        assert_eq!(list.servers()[0].beacon_id, 0);
+        list.expire(start + Duration::from_secs(12), Duration::from_secs(10));
+        assert!(list.servers().is_empty());
+
+        let events: Vec<ServerEvent> = rx.try_iter().collect();
+        assert_eq!(events, vec![
+            ServerEvent::ServerAppeared(address),
+            ServerEvent::ServerRestarted(address),
+            ServerEvent::ServerLost(address),
+        ]);
+    }
+}
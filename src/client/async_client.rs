@@ -0,0 +1,213 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::ClientConfig;
+use crate::dbr::{Dbr, FromValue, IntoValue};
+use crate::protocol::{DataType, Message};
+use super::channel::{read_notify, read_response, write_notify, write_response};
+use super::subscription::{consumed, Delivery, Subscription, Update};
+use super::{Channel, Circuit, Client, ConnectionState, Error, SearchResult, Shared};
+
+/// Client whose searches, connections and requests are futures and whose subscriptions are streams.
+///
+/// Built on the same circuits and protocol codec as the blocking [`Client`]; must be used inside a Tokio runtime
+/// with the time driver enabled.
+pub struct AsyncClient {
+    client: Client,
+}
+
+impl AsyncClient {
+    /// Creates a client configured from the EPICS_CA_* environment variables.
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self { client: Client::new()? })
+    }
+
+    /// Creates a client with an explicit configuration.
+    pub fn with_config(config: ClientConfig) -> Result<Self, Error> {
+        Ok(Self { client: Client::with_config(config)? })
+    }
+
+    /// Returns the blocking client the async one is built on.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Searches for the server hosting `name`, waiting up to the configured search timeout for a reply.
+    pub async fn search(&self, name: &str) -> Result<SearchResult, Error> {
+        self.search_timeout(name, self.client.config().search_timeout).await
+    }
+
+    /// Searches for the server hosting `name`, waiting up to `timeout` for a reply.
+    pub async fn search_timeout(&self, name: &str, timeout: Duration) -> Result<SearchResult, Error> {
+        let (tx, rx) = oneshot::channel();
+        let cid = self.client.shared.start_search(name, Box::new(move |result| {
+            let _ = tx.send(result);
+        }));
+        let _pending = PendingSearch { shared: &self.client.shared, cid };
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => Ok(result),
+            _ => Err(Error::TimeoutError(format!("No server replied to search for {}", name))),
+        }
+    }
+
+    /// Creates a channel to the process variable `name`, connecting it in the background.
+    pub fn create_channel(&self, name: &str) -> AsyncChannel {
+        AsyncChannel { channel: self.client.create_channel(name) }
+    }
+
+    /// Creates a channel to the process variable `name` and waits up to the configured connection timeout for it to connect.
+    pub async fn connect(&self, name: &str) -> Result<AsyncChannel, Error> {
+        let channel = self.create_channel(name);
+        channel.connected(self.client.config().conn_tmo).await?;
+        Ok(channel)
+    }
+}
+
+/// Channel whose requests are futures. Dropping the handle clears the channel on the server.
+pub struct AsyncChannel {
+    channel: Channel,
+}
+
+impl AsyncChannel {
+    /// Returns the blocking channel handle, for state queries and callbacks.
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    /// Waits up to `timeout` for the channel to be connected.
+    pub async fn connected(&self, timeout: Duration) -> Result<(), Error> {
+        // Listen before checking the state so no transition is missed
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.channel.listen(Arc::new(move |event| tx.send(event).is_ok()));
+
+        let wait = async {
+            while self.channel.state() != ConnectionState::Connected {
+                if matches!(self.channel.state(), ConnectionState::Failed | ConnectionState::Closed) || rx.recv().await.is_none() {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait).await;
+
+        // Report the same errors as the blocking API
+        self.channel.wait_connected(Duration::from_secs(0))
+    }
+
+    /// Reads the value as `T`, waiting up to the configured connection timeout for the server's reply.
+    pub async fn get<T: FromValue>(&self) -> Result<T, Error> {
+        self.get_timeout(self.channel.config().conn_tmo).await
+    }
+
+    /// Reads the value as `T`, waiting up to `timeout` for the server's reply.
+    pub async fn get_timeout<T: FromValue>(&self, timeout: Duration) -> Result<T, Error> {
+        let (data_type, count) = self.channel.get_type::<T>();
+        let dbr = self.get_dbr_timeout(data_type, count, timeout).await?;
+        Ok(T::from_value(dbr.into_value())?)
+    }
+
+    /// Reads `count` elements as `data_type`, waiting up to the configured connection timeout for the server's reply.
+    pub async fn get_dbr(&self, data_type: DataType, count: u32) -> Result<Dbr, Error> {
+        self.get_dbr_timeout(data_type, count, self.channel.config().conn_tmo).await
+    }
+
+    /// Reads `count` elements as `data_type` with CA_PROTO_READ_NOTIFY, waiting up to `timeout` for the server's reply.
+    pub async fn get_dbr_timeout(&self, data_type: DataType, count: u32, timeout: Duration) -> Result<Dbr, Error> {
+        self.channel.check_get(data_type, count)?;
+        read_response(self.request(read_notify(data_type, count), timeout).await?)
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE without waiting for the server to process it.
+    pub fn put<T: IntoValue>(&self, value: T) -> Result<(), Error> {
+        self.channel.put(value)
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE_NOTIFY, waiting up to the configured connection timeout for the write to complete.
+    pub async fn put_callback<T: IntoValue>(&self, value: T) -> Result<(), Error> {
+        self.put_callback_timeout(value, self.channel.config().conn_tmo).await
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE_NOTIFY, waiting up to `timeout` for the write to complete.
+    pub async fn put_callback_timeout<T: IntoValue>(&self, value: T, timeout: Duration) -> Result<(), Error> {
+        let encoded = self.channel.encode(value)?;
+        write_response(self.request(write_notify(encoded), timeout).await?)
+    }
+
+    /// Subscribes to `count` elements as `data_type`, streaming every update selected by `mask`.
+    ///
+    /// Unread updates count towards the circuit's flow control backlog, like those of a blocking [`Monitor`](super::Monitor).
+    pub fn subscribe(&self, data_type: DataType, count: u32, mask: u16) -> Result<AsyncMonitor, Error> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let subscription = self.channel.start_subscription(data_type, count, mask, Delivery::AsyncQueue(tx))?;
+        Ok(AsyncMonitor { subscription, receiver: rx })
+    }
+
+    /// Sends a request and waits up to `timeout` for the response routed to its IOID.
+    async fn request<F>(&self, build: F, timeout: Duration) -> Result<Message, Error>
+    where F: FnOnce(u32, u32) -> Message {
+        let (tx, rx) = oneshot::channel();
+        let tx = Mutex::new(Some(tx));
+        let (circuit, ioid) = self.channel.send_request(build, Arc::new(move |event| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(event);
+            }
+        }))?;
+        let _pending = PendingRequest { circuit, ioid };
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(event)) => self.channel.response(event),
+            Ok(Err(_)) => Err(Error::ChannelError(format!("Request on channel {} was abandoned", self.channel.name()))),
+            Err(_) => Err(Error::TimeoutError(format!("No response to request on channel {}", self.channel.name()))),
+        }
+    }
+}
+
+/// Stream of subscription updates. Dropping it cancels the subscription.
+pub struct AsyncMonitor {
+    subscription: Subscription,
+    receiver: mpsc::UnboundedReceiver<Update>,
+}
+
+impl Stream for AsyncMonitor {
+    type Item = Result<Dbr, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx).map(|update| update.map(consumed))
+    }
+}
+
+impl Drop for AsyncMonitor {
+    fn drop(&mut self) {
+        let receiver = &mut self.receiver;
+        self.subscription.cancel_and_drain(std::iter::from_fn(|| receiver.try_recv().ok()));
+    }
+}
+
+/// Forgets a search when its future completes or is dropped.
+struct PendingSearch<'a> {
+    shared: &'a Shared,
+    cid: u32,
+}
+
+impl Drop for PendingSearch<'_> {
+    fn drop(&mut self) {
+        self.shared.end_search(self.cid);
+    }
+}
+
+/// Unregisters a request's IOID when its future completes or is dropped.
+struct PendingRequest {
+    circuit: Arc<Circuit>,
+    ioid: u32,
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.circuit.unregister(self.ioid);
+    }
+}
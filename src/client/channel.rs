@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+use crate::dbr::{Category, Dbr, FieldType, FromValue, IntoValue, Value};
+use crate::protocol::{eca, AccessRights, DataType, Message};
+use super::{Circuit, CircuitEvent, Error, SearchResult, Shared};
+use super::circuit::Handler;
+use super::subscription::{Delivery, Monitor, Subscription};
+
+use log::{info, warn, debug};
+
+/// Highest circuit priority a channel can request.
+pub const MAX_PRIORITY: u16 = 99;
+
+/// Connection state of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The channel has not been connected yet; the server is being searched for or the channel is being created.
+    NeverConnected,
+    /// The channel is connected and can be used.
+    Connected,
+    /// The channel was connected but lost its server.
+    Disconnected,
+    /// The server refused to create the channel.
+    Failed,
+    /// The channel has been cleared.
+    Closed,
+}
+
+/// Change in the connection of a channel, delivered to the listeners registered with
+/// [`Channel::on_event`] and [`Channel::events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelEvent {
+    /// The channel connected or reconnected.
+    Connected { native_type: DataType, element_count: u32, server: SocketAddr },
+    /// The channel lost its server and is searching for it again.
+    Disconnected,
+    /// The server granted different permissions. Disconnection reports no access.
+    AccessRightsChanged(AccessRights),
+}
+
+/// Receiver of channel events, returning false once it has gone away.
+pub(crate) type Listener = Arc<dyn Fn(ChannelEvent) -> bool + Send + Sync>;
+
+/// Properties of a connected channel, as reported by the server in its CA_PROTO_CREATE_CHAN reply.
+struct Connection {
+    sid: u32,
+    native_type: DataType,
+    element_count: u32,
+}
+
+struct ChannelState {
+    connection_state: ConnectionState,
+    /// Circuit the channel is attached to, from the CA_PROTO_CREATE_CHAN request until it is cleared or lost.
+    circuit: Option<Arc<Circuit>>,
+    connection: Option<Connection>,
+    /// Permissions from the last CA_PROTO_ACCESS_RIGHTS message, reset when the channel disconnects.
+    access_rights: Option<AccessRights>,
+    /// Cid of the pending search for the channel's server, if any.
+    search: Option<u32>,
+}
+
+/// Parameters of a subscription, kept while its handle is alive.
+#[derive(Clone)]
+struct SubscriptionRequest {
+    data_type: DataType,
+    count: u32,
+    mask: u16,
+    delivery: Delivery,
+}
+
+/// State of a channel shared with its pending search and circuit handler.
+pub(crate) struct ChannelInner {
+    name: String,
+    cid: u32,
+    priority: u16,
+    shared: Arc<Shared>,
+    state: Mutex<ChannelState>,
+    changed: Condvar,
+    subscriptions: Mutex<HashMap<u32, SubscriptionRequest>>,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+/// Handle to a process variable on a Channel Access server.
+///
+/// Dropping the handle clears the channel on the server.
+pub struct Channel {
+    inner: Arc<ChannelInner>,
+}
+
+impl Channel {
+    /// Creates the channel and starts connecting it in the background.
+    pub(crate) fn create(shared: Arc<Shared>, name: &str, priority: u16) -> Self {
+        let inner = Arc::new(ChannelInner {
+            name: name.into(),
+            cid: shared.next_id(),
+            priority,
+            shared,
+            state: Mutex::new(ChannelState {
+                connection_state: ConnectionState::NeverConnected,
+                circuit: None,
+                connection: None,
+                access_rights: None,
+                search: None,
+            }),
+            changed: Condvar::new(),
+            subscriptions: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(vec!()),
+        });
+
+        inner.search();
+        Self { inner }
+    }
+
+    /// Name of the process variable.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Client-assigned channel ID.
+    pub fn cid(&self) -> u32 {
+        self.inner.cid
+    }
+
+    /// Current connection state.
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state.lock().unwrap().connection_state
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Connected
+    }
+
+    /// Server-assigned channel ID, while connected.
+    pub fn sid(&self) -> Option<u32> {
+        self.inner.state.lock().unwrap().connection.as_ref().map(|c| c.sid)
+    }
+
+    /// Native data type of the process variable, while connected.
+    pub fn native_type(&self) -> Option<DataType> {
+        self.inner.state.lock().unwrap().connection.as_ref().map(|c| c.native_type)
+    }
+
+    /// Number of elements of the process variable, while connected.
+    pub fn element_count(&self) -> Option<u32> {
+        self.inner.state.lock().unwrap().connection.as_ref().map(|c| c.element_count)
+    }
+
+    /// Address of the server hosting the channel, while connected.
+    pub fn server_address(&self) -> Option<SocketAddr> {
+        let state = self.inner.state.lock().unwrap();
+        state.connection.as_ref().and(state.circuit.as_ref()).map(|c| c.address())
+    }
+
+    /// Permissions granted by the server, once it has reported them.
+    pub fn access_rights(&self) -> Option<AccessRights> {
+        self.inner.state.lock().unwrap().access_rights
+    }
+
+    /// Adds a callback notified whenever the access rights of the channel change.
+    ///
+    /// The callback runs on the client's event loop thread. Disconnection reports no access.
+    pub fn on_access_rights_change<F>(&self, callback: F)
+    where F: Fn(AccessRights) + Send + Sync + 'static {
+        self.on_event(move |event| {
+            if let ChannelEvent::AccessRightsChanged(rights) = event {
+                callback(rights);
+            }
+        });
+    }
+
+    /// Adds a callback notified of every connection and access rights change from now on.
+    ///
+    /// The callback runs on the thread that observed the change, usually the client's event loop thread.
+    pub fn on_event<F>(&self, callback: F)
+    where F: Fn(ChannelEvent) + Send + Sync + 'static {
+        self.listen(Arc::new(move |event| {
+            callback(event);
+            true
+        }));
+    }
+
+    /// Returns a receiver for the connection and access rights changes seen from now on.
+    pub fn events(&self) -> Receiver<ChannelEvent> {
+        let (tx, rx) = channel::<ChannelEvent>();
+        let tx = Mutex::new(tx);
+        self.listen(Arc::new(move |event| tx.lock().unwrap().send(event).is_ok()));
+        rx
+    }
+
+    pub(crate) fn listen(&self, listener: Listener) {
+        self.inner.listeners.lock().unwrap().push(listener);
+    }
+
+    /// Blocks until the channel is connected or `timeout` expires.
+    pub fn wait_connected(&self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            match state.connection_state {
+                ConnectionState::Connected => return Ok(()),
+                ConnectionState::Failed => return Err(Error::ChannelError(format!("Server refused to create channel {}", self.inner.name))),
+                ConnectionState::Closed => return Err(Error::ChannelError(format!("Channel {} has been cleared", self.inner.name))),
+                ConnectionState::NeverConnected | ConnectionState::Disconnected => (),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::TimeoutError(format!("Channel {} did not connect", self.inner.name)))
+            }
+            state = self.inner.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Reads the value as `T`, waiting up to the configured connection timeout for the server's reply.
+    ///
+    /// Scalar types read the first element; `Vec` types read every element of the channel.
+    pub fn get<T: FromValue>(&self) -> Result<T, Error> {
+        self.get_timeout(self.inner.shared.config().conn_tmo)
+    }
+
+    /// Reads the value as `T`, waiting up to `timeout` for the server's reply.
+    pub fn get_timeout<T: FromValue>(&self, timeout: Duration) -> Result<T, Error> {
+        let (data_type, count) = self.get_type::<T>();
+        let dbr = self.get_dbr_timeout(data_type, count, timeout)?;
+        Ok(T::from_value(dbr.into_value())?)
+    }
+
+    /// Returns the DBR type and element count to request when reading a `T`.
+    pub(crate) fn get_type<T: FromValue>(&self) -> (DataType, u32) {
+        let count = if T::is_array() { self.element_count().unwrap_or(1) } else { 1 };
+        (DataType::from_parts(Category::Plain, T::field_type()), count)
+    }
+
+    /// Reads `count` elements as `data_type`, waiting up to the configured connection timeout for the server's reply.
+    pub fn get_dbr(&self, data_type: DataType, count: u32) -> Result<Dbr, Error> {
+        self.get_dbr_timeout(data_type, count, self.inner.shared.config().conn_tmo)
+    }
+
+    /// Reads `count` elements as `data_type` with CA_PROTO_READ_NOTIFY, waiting up to `timeout` for the server's reply.
+    pub fn get_dbr_timeout(&self, data_type: DataType, count: u32, timeout: Duration) -> Result<Dbr, Error> {
+        self.check_get(data_type, count)?;
+        let response = self.inner.request(read_notify(data_type, count), timeout)?;
+        read_response(response)
+    }
+
+    /// Fails fast if a read of `count` elements as `data_type` cannot succeed.
+    pub(crate) fn check_get(&self, data_type: DataType, count: u32) -> Result<(), Error> {
+        if data_type.payload_size(count as usize) > self.inner.shared.config().max_array_bytes {
+            return Err(Error::from_eca(eca::ECA_TOLARGE))
+        }
+        self.check_access(|rights| rights.read, "Read")
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE without waiting for the server to process it.
+    ///
+    /// The value is converted to the channel's native type before it is sent.
+    pub fn put<T: IntoValue>(&self, value: T) -> Result<(), Error> {
+        let (circuit, sid) = self.inner.connection()?;
+        let (data_type, data_count, payload) = self.encode(value)?;
+        circuit.send(&Message::Write {
+            data_type,
+            data_count,
+            sid,
+            ioid: self.inner.shared.next_id(),
+            payload,
+        })
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE_NOTIFY, waiting up to the configured connection timeout for the write to complete.
+    pub fn put_callback<T: IntoValue>(&self, value: T) -> Result<(), Error> {
+        self.put_callback_timeout(value, self.inner.shared.config().conn_tmo)
+    }
+
+    /// Writes `value` with CA_PROTO_WRITE_NOTIFY, waiting up to `timeout` for the write to complete.
+    pub fn put_callback_timeout<T: IntoValue>(&self, value: T, timeout: Duration) -> Result<(), Error> {
+        let encoded = self.encode(value)?;
+        let response = self.inner.request(write_notify(encoded), timeout)?;
+        write_response(response)
+    }
+
+    /// Converts `value` to the channel's native type, returning the DBR type, element count and payload to send.
+    ///
+    /// Strings written to enum channels are sent as DBR_STRING, for the server to look up among its enum states.
+    pub(crate) fn encode<T: IntoValue>(&self, value: T) -> Result<(u16, u32, Vec<u8>), Error> {
+        self.check_access(|rights| rights.write, "Write")?;
+        let native_type = self.native_type()
+            .ok_or_else(|| Error::ChannelError(format!("Channel {} is not connected", self.inner.name)))?;
+        let value = match (value.into_value(), native_type.field_type()) {
+            (value @ Value::String(_), FieldType::Enum) => value,
+            (value, field_type) => value.convert(field_type)?,
+        };
+        if value.is_empty() {
+            return Err(Error::from_eca(eca::ECA_BADCOUNT))
+        }
+        let data_type = DataType::from_parts(Category::Plain, value.field_type());
+        if data_type.payload_size(value.len()) > self.inner.shared.config().max_array_bytes {
+            return Err(Error::from_eca(eca::ECA_TOLARGE))
+        }
+        Ok((data_type.into(), value.len() as u32, value.as_bytes()))
+    }
+
+    /// Subscribes to `count` elements as `data_type` with CA_PROTO_EVENT_ADD, calling `callback` with every update.
+    ///
+    /// `mask` combines the `protocol::DBE_*` bits selecting which changes trigger an update.
+    /// The callback runs on the client's event loop thread and also reports errors and disconnections.
+    /// The subscription can be created before the channel connects and is re-established whenever it reconnects.
+    pub fn subscribe<F>(&self, data_type: DataType, count: u32, mask: u16, callback: F) -> Result<Subscription, Error>
+    where F: Fn(Result<Dbr, Error>) + Send + Sync + 'static {
+        self.start_subscription(data_type, count, mask, Delivery::Callback(Arc::new(callback)))
+    }
+
+    /// Subscribes like [`Channel::subscribe`], queueing the updates in the returned monitor.
+    ///
+    /// Queued updates count towards the circuit's flow control backlog until they are read.
+    pub fn monitor(&self, data_type: DataType, count: u32, mask: u16) -> Result<Monitor, Error> {
+        let (tx, rx) = channel();
+        let subscription = self.start_subscription(data_type, count, mask, Delivery::Queue(Arc::new(Mutex::new(tx))))?;
+        Ok(Monitor::new(subscription, rx))
+    }
+
+    pub(crate) fn start_subscription(&self, data_type: DataType, count: u32, mask: u16, delivery: Delivery) -> Result<Subscription, Error> {
+        if data_type.payload_size(count as usize) > self.inner.shared.config().max_array_bytes {
+            return Err(Error::from_eca(eca::ECA_TOLARGE))
+        }
+        ChannelInner::subscribe(&self.inner, SubscriptionRequest { data_type, count, mask, delivery })
+    }
+
+    /// Registers `handler` for a fresh IOID and sends the request built from the channel's SID and that IOID.
+    ///
+    /// The caller unregisters the IOID from the returned circuit once the response has arrived.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn send_request<F>(&self, build: F, handler: Handler) -> Result<(Arc<Circuit>, u32), Error>
+    where F: FnOnce(u32, u32) -> Message {
+        self.inner.send_request(build, handler)
+    }
+
+    /// Extracts the response message from the event delivered to a request's handler.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn response(&self, event: CircuitEvent) -> Result<Message, Error> {
+        self.inner.response(event)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn config(&self) -> &crate::ClientConfig {
+        self.inner.shared.config()
+    }
+
+    /// Fails fast if the server has reported that `granted` access is denied.
+    fn check_access<F: Fn(AccessRights) -> bool>(&self, granted: F, kind: &str) -> Result<(), Error> {
+        match self.access_rights() {
+            Some(rights) if !granted(rights) => Err(Error::AccessError(format!("{} access denied on channel {}", kind, self.inner.name))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Clears the channel on the server. Called automatically when the handle is dropped.
+    pub fn clear(&self) {
+        self.inner.clear();
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.inner.clear();
+    }
+}
+
+impl ChannelInner {
+    /// Starts searching for the server hosting the channel. The reply is handled on the client's event loop.
+    fn search(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        if state.connection_state == ConnectionState::Closed {
+            return;
+        }
+        let weak = Arc::downgrade(self);
+        state.search = Some(self.shared.start_search(&self.name, Box::new(move |found| {
+            if let Some(channel) = Weak::upgrade(&weak) {
+                channel.found(found);
+            }
+        })));
+    }
+
+    /// Creates the channel over a virtual circuit to the server that answered its search.
+    fn found(self: Arc<Self>, found: SearchResult) {
+        self.state.lock().unwrap().search = None;
+        let created = self.shared.circuit(found.address, self.priority)
+            .and_then(|circuit| {
+                let weak = Arc::downgrade(&self);
+                circuit.attach_channel(self.cid, Arc::new(move |event| {
+                    if let Some(channel) = Weak::upgrade(&weak) {
+                        channel.handle(event);
+                    }
+                }));
+
+                // The channel may have been cleared while searching
+                let mut state = self.state.lock().unwrap();
+                if state.connection_state == ConnectionState::Closed {
+                    circuit.detach_channel(self.cid);
+                    return Ok(());
+                }
+                state.circuit = Some(circuit.clone());
+
+                circuit.send(&Message::CreateChan {
+                    cid: self.cid,
+                    minor_version: crate::MINOR_PROTOCOL_VERSION as u32,
+                    name: self.name.clone(),
+                })
+            });
+
+        if let Err(e) = created {
+            warn!("Could not connect channel {}: {:?}", self.name, e);
+            self.set_state(ConnectionState::Failed, None);
+        }
+    }
+
+    /// Handles an event routed to the channel by its circuit.
+    fn handle(self: Arc<Self>, event: CircuitEvent) {
+        match event {
+            CircuitEvent::Message(Message::CreateChanResponse { data_type, data_count, sid, .. }) => {
+                let native_type = match DataType::try_from(data_type) {
+                    Ok(native_type) => native_type,
+                    Err(e) => {
+                        warn!("Channel {} has unsupported native type {}: {:?}", self.name, data_type, e);
+                        self.release_circuit();
+                        self.set_state(ConnectionState::Failed, None);
+                        return;
+                    }
+                };
+                info!("Channel {} connected (sid {}, {:?}[{}])", self.name, sid, native_type, data_count);
+                self.connected(Connection { sid, native_type, element_count: data_count });
+            },
+            CircuitEvent::Message(Message::AccessRights { access_rights, .. }) => {
+                let rights = AccessRights::from_bits(access_rights);
+                debug!("Channel {} access rights: {:?}", self.name, rights);
+                self.set_access_rights(Some(rights));
+            },
+            CircuitEvent::Message(Message::CreateChFail { .. }) => {
+                warn!("Server refused to create channel {}", self.name);
+                self.release_circuit();
+                self.set_state(ConnectionState::Failed, None);
+            },
+            CircuitEvent::Message(Message::ServerDisconn { .. }) | CircuitEvent::Disconnected => {
+                info!("Channel {} disconnected, searching again", self.name);
+                // Subscription handlers of a lost circuit are notified by the circuit itself
+                if event != CircuitEvent::Disconnected {
+                    self.notify_subscriptions(|| Err(Error::ChannelError(format!("Channel {} disconnected", self.name))));
+                }
+                self.release_circuit();
+                self.set_state(ConnectionState::Disconnected, None);
+                self.notify(ChannelEvent::Disconnected);
+                self.set_access_rights(None);
+                self.search();
+            },
+            CircuitEvent::Message(message) => debug!("Channel {} ignoring {:?}", self.name, message.command()),
+        }
+    }
+
+    /// Returns the circuit and SID of the connected channel.
+    fn connection(&self) -> Result<(Arc<Circuit>, u32), Error> {
+        let state = self.state.lock().unwrap();
+        match (&state.circuit, &state.connection) {
+            (Some(circuit), Some(connection)) => Ok((circuit.clone(), connection.sid)),
+            _ => Err(Error::ChannelError(format!("Channel {} is not connected", self.name))),
+        }
+    }
+
+    /// Sends the request built from the channel's SID and a fresh IOID, then waits up to `timeout` for the response routed to that IOID.
+    fn request<F>(&self, build: F, timeout: Duration) -> Result<Message, Error>
+    where F: FnOnce(u32, u32) -> Message {
+        let (tx, rx) = channel::<CircuitEvent>();
+        let tx = Mutex::new(tx);
+        let (circuit, ioid) = self.send_request(build, Arc::new(move |event| {
+            let _ = tx.lock().unwrap().send(event);
+        }))?;
+
+        let result = match rx.recv_timeout(timeout) {
+            Ok(event) => self.response(event),
+            Err(_) => Err(Error::TimeoutError(format!("No response to request on channel {}", self.name))),
+        };
+
+        circuit.unregister(ioid);
+        result
+    }
+
+    /// Registers `handler` for a fresh IOID and sends the request built from the channel's SID and that IOID.
+    fn send_request<F>(&self, build: F, handler: Handler) -> Result<(Arc<Circuit>, u32), Error>
+    where F: FnOnce(u32, u32) -> Message {
+        let (circuit, sid) = self.connection()?;
+        let ioid = self.shared.next_id();
+        circuit.register(ioid, handler);
+        match circuit.send(&build(sid, ioid)) {
+            Ok(()) => Ok((circuit, ioid)),
+            Err(e) => {
+                circuit.unregister(ioid);
+                Err(e)
+            },
+        }
+    }
+
+    /// Extracts the response message from the event delivered to a request's handler.
+    fn response(&self, event: CircuitEvent) -> Result<Message, Error> {
+        match event {
+            CircuitEvent::Message(message) => Ok(message),
+            CircuitEvent::Disconnected => Err(Error::ChannelError(format!("Channel {} disconnected during request", self.name))),
+        }
+    }
+
+    /// Records the subscription and sends its CA_PROTO_EVENT_ADD request if the channel is connected.
+    /// Otherwise the request is sent once the channel connects.
+    fn subscribe(inner: &Arc<Self>, request: SubscriptionRequest) -> Result<Subscription, Error> {
+        let state = inner.state.lock().unwrap();
+        if state.connection_state == ConnectionState::Closed {
+            return Err(Error::ChannelError(format!("Channel {} has been cleared", inner.name)))
+        }
+        let id = inner.shared.next_id();
+        inner.subscriptions.lock().unwrap().insert(id, request.clone());
+
+        // Dropping the handle on failure cancels the subscription again
+        let subscription = Subscription::new(inner.clone(), id);
+        if let (Some(circuit), Some(connection)) = (&state.circuit, &state.connection) {
+            let result = inner.start_subscription(circuit, connection.sid, id, &request);
+            drop(state);
+            result?;
+        }
+        Ok(subscription)
+    }
+
+    /// Stores the connection and re-establishes every subscription on the channel's circuit.
+    fn connected(&self, connection: Connection) {
+        let mut state = self.state.lock().unwrap();
+        if state.connection_state == ConnectionState::Closed {
+            return;
+        }
+        let circuit = match &state.circuit {
+            Some(circuit) => circuit.clone(),
+            None => return,
+        };
+        for (id, request) in self.subscriptions.lock().unwrap().iter() {
+            if let Err(e) = self.start_subscription(&circuit, connection.sid, *id, request) {
+                warn!("Could not restore subscription {} on channel {}: {:?}", id, self.name, e);
+            }
+        }
+        let event = ChannelEvent::Connected {
+            native_type: connection.native_type,
+            element_count: connection.element_count,
+            server: circuit.address(),
+        };
+        state.connection_state = ConnectionState::Connected;
+        state.connection = Some(connection);
+        self.changed.notify_all();
+        drop(state);
+
+        self.notify(event);
+    }
+
+    /// Routes the updates of subscription `id` to its callback and sends CA_PROTO_EVENT_ADD.
+    fn start_subscription(&self, circuit: &Arc<Circuit>, sid: u32, id: u32, request: &SubscriptionRequest) -> Result<(), Error> {
+        let delivery = request.delivery.clone();
+        let name = self.name.clone();
+        let weak = Arc::downgrade(circuit);
+        circuit.register(id, Arc::new(move |event| match event {
+            CircuitEvent::Message(Message::EventAddResponse { data_type, data_count, status, payload, .. }) => {
+                delivery.deliver(&weak, decode(data_type, data_count, status, &payload));
+            },
+            CircuitEvent::Message(_) => (),
+            CircuitEvent::Disconnected => delivery.deliver(&weak, Err(Error::ChannelError(format!("Channel {} disconnected", name)))),
+        }));
+
+        circuit.send(&Message::EventAdd {
+            data_type: request.data_type.into(),
+            data_count: request.count,
+            sid,
+            subscription_id: id,
+            mask: request.mask,
+        })
+    }
+
+    /// Forgets subscription `id` and sends CA_PROTO_EVENT_CANCEL if the channel is connected.
+    pub(crate) fn cancel(&self, id: u32) {
+        let request = match self.subscriptions.lock().unwrap().remove(&id) {
+            Some(request) => request,
+            None => return,
+        };
+
+        let state = self.state.lock().unwrap();
+        if let Some(circuit) = &state.circuit {
+            circuit.unregister(id);
+            if let Some(connection) = &state.connection {
+                let cancel = Message::EventCancel {
+                    data_type: request.data_type.into(),
+                    data_count: request.count,
+                    sid: connection.sid,
+                    subscription_id: id,
+                };
+                if let Err(e) = circuit.send(&cancel) {
+                    debug!("Could not cancel subscription {} on channel {}: {:?}", id, self.name, e);
+                }
+            }
+        }
+    }
+
+    /// Detaches the channel from its circuit, if any.
+    fn release_circuit(&self) {
+        let circuit = self.state.lock().unwrap().circuit.take();
+        if let Some(circuit) = circuit {
+            for id in self.subscriptions.lock().unwrap().keys() {
+                circuit.unregister(*id);
+            }
+            circuit.detach_channel(self.cid);
+        }
+    }
+
+    /// Delivers the update built by `update` to every subscription of the channel.
+    fn notify_subscriptions<F: Fn() -> Result<Dbr, Error>>(&self, update: F) {
+        let deliveries: Vec<Delivery> = self.subscriptions.lock().unwrap().values().map(|r| r.delivery.clone()).collect();
+        for delivery in deliveries {
+            delivery.deliver(&Weak::new(), update());
+        }
+    }
+
+    /// Stores the channel's access rights, notifying the callback if they changed.
+    fn set_access_rights(&self, access_rights: Option<AccessRights>) {
+        let previous = std::mem::replace(&mut self.state.lock().unwrap().access_rights, access_rights);
+        if previous.unwrap_or_default() != access_rights.unwrap_or_default() {
+            self.notify(ChannelEvent::AccessRightsChanged(access_rights.unwrap_or_default()));
+        }
+    }
+
+    /// Delivers `event` to every listener, forgetting the ones that have gone away.
+    fn notify(&self, event: ChannelEvent) {
+        // Call the listeners unlocked so they can register further listeners
+        let listeners = self.listeners.lock().unwrap().clone();
+        let gone: Vec<Listener> = listeners.into_iter().filter(|listener| !listener(event)).collect();
+        if !gone.is_empty() {
+            self.listeners.lock().unwrap().retain(|listener| !gone.iter().any(|g| Arc::ptr_eq(g, listener)));
+        }
+    }
+
+    fn set_state(&self, connection_state: ConnectionState, connection: Option<Connection>) {
+        let mut state = self.state.lock().unwrap();
+        // A cleared channel stays closed
+        if state.connection_state == ConnectionState::Closed {
+            return;
+        }
+        state.connection_state = connection_state;
+        state.connection = connection;
+        self.changed.notify_all();
+    }
+
+    /// Sends CA_PROTO_CLEAR_CHANNEL if connected and releases the channel's circuit.
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.connection_state == ConnectionState::Closed {
+            return;
+        }
+        state.connection_state = ConnectionState::Closed;
+        if let Some(cid) = state.search.take() {
+            self.shared.end_search(cid);
+        }
+        let subscriptions: Vec<u32> = self.subscriptions.lock().unwrap().drain().map(|(id, _)| id).collect();
+        if let Some(circuit) = state.circuit.take() {
+            for id in subscriptions {
+                circuit.unregister(id);
+            }
+            if let Some(connection) = state.connection.take() {
+                if let Err(e) = circuit.send(&Message::ClearChannel { sid: connection.sid, cid: self.cid }) {
+                    debug!("Could not clear channel {}: {:?}", self.name, e);
+                }
+            }
+            circuit.detach_channel(self.cid);
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Builds a CA_PROTO_READ_NOTIFY request from a SID and IOID.
+pub(crate) fn read_notify(data_type: DataType, count: u32) -> impl FnOnce(u32, u32) -> Message {
+    move |sid, ioid| Message::ReadNotify { data_type: data_type.into(), data_count: count, sid, ioid }
+}
+
+/// Builds a CA_PROTO_WRITE_NOTIFY request for an encoded value from a SID and IOID.
+pub(crate) fn write_notify((data_type, data_count, payload): (u16, u32, Vec<u8>)) -> impl FnOnce(u32, u32) -> Message {
+    move |sid, ioid| Message::WriteNotify { data_type, data_count, sid, ioid, payload }
+}
+
+/// Decodes the response to a CA_PROTO_READ_NOTIFY request.
+pub(crate) fn read_response(response: Message) -> Result<Dbr, Error> {
+    match response {
+        Message::ReadNotifyResponse { data_type, data_count, status, payload, .. } => decode(data_type, data_count, status, &payload),
+        other => Err(Error::ProtocolError(format!("Unexpected response to read request: {:?}", other.command()))),
+    }
+}
+
+/// Checks the response to a CA_PROTO_WRITE_NOTIFY request.
+pub(crate) fn write_response(response: Message) -> Result<(), Error> {
+    match response {
+        Message::WriteNotifyResponse { status, .. } if eca::is_success(status) => Ok(()),
+        Message::WriteNotifyResponse { status, .. } => Err(Error::from_eca(status)),
+        other => Err(Error::ProtocolError(format!("Unexpected response to write request: {:?}", other.command()))),
+    }
+}
+
+/// Decodes the payload of a CA_PROTO_READ_NOTIFY or CA_PROTO_EVENT_ADD response.
+fn decode(data_type: u16, data_count: u32, status: u32, payload: &[u8]) -> Result<Dbr, Error> {
+    if !eca::is_success(status) {
+        return Err(Error::from_eca(status))
+    }
+    Ok(Dbr::from_bytes(DataType::try_from(data_type)?, data_count as usize, payload)?)
+}
@@ -0,0 +1,472 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use mio::{Interest, Registry, Token};
+use mio::event::Event;
+use mio::net::TcpStream;
+
+use crate::ClientConfig;
+use crate::protocol::{
+    Message,
+    MessageReader,
+    Origin,
+};
+use super::Error;
+
+use log::{info, warn, debug, trace};
+
+/// Notification delivered to the handler registered for an ID on a circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CircuitEvent {
+    /// A message addressed to the handler's ID was received.
+    Message(Message),
+    /// The circuit was lost; no further messages will be delivered.
+    Disconnected,
+}
+
+/// Longest wait for the reply to a CA_PROTO_ECHO before the circuit is declared unresponsive.
+const ECHO_TIMEOUT: f64 = 5.0;
+
+/// Callback receiving the events routed to one channel, request or subscription ID.
+pub type Handler = Arc<dyn Fn(CircuitEvent) + Send + Sync>;
+
+/// Socket of a circuit and the bytes still waiting to be written to it.
+struct Io {
+    stream: TcpStream,
+    /// The non-blocking connect has completed.
+    connected: bool,
+    outgoing: Vec<u8>,
+    /// The socket is registered for writability because `outgoing` could not be flushed.
+    writable_interest: bool,
+}
+
+/// Whether the server has been asked to pause subscription updates, and why.
+#[derive(Default)]
+struct FlowControl {
+    /// CA_PROTO_EVENTS_OFF has been sent.
+    events_off: bool,
+    /// Updates were paused explicitly and are only resumed explicitly.
+    paused: bool,
+}
+
+/// A TCP virtual circuit to one server at one priority, shared by every channel hosted there.
+///
+/// The socket is non-blocking and driven by the client's event loop: sends are buffered and never wait for
+/// the server, and incoming messages are routed to handlers, on the event loop thread, by the channel,
+/// request or subscription ID they carry. The circuit closes itself once the last attached channel is detached.
+pub struct Circuit {
+    address: SocketAddr,
+    priority: u16,
+    token: Token,
+    registry: Registry,
+    io: Mutex<Io>,
+    reader: Mutex<MessageReader>,
+    server_minor_version: Mutex<Option<u16>>,
+    handlers: Mutex<HashMap<u32, Handler>>,
+    channels: Mutex<HashSet<u32>>,
+    closed: AtomicBool,
+    backlog: AtomicUsize,
+    flow_control: Mutex<FlowControl>,
+    flow_control_high: usize,
+    flow_control_low: usize,
+    conn_tmo: Duration,
+    last_received: Mutex<Instant>,
+    /// Time the unanswered CA_PROTO_ECHO was sent, if any.
+    echo_sent: Mutex<Option<Instant>>,
+}
+
+impl Circuit {
+    /// Starts connecting to `address` and queues the version and identification handshake.
+    ///
+    /// The socket is registered with the event loop's `registry` under `token`; the connection completes in the background.
+    pub(crate) fn connect(address: SocketAddr, priority: u16, config: &ClientConfig, registry: &Registry, token: Token) -> Result<Arc<Self>, Error> {
+        let mut stream = TcpStream::connect(address)?;
+        registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let circuit = Arc::new(Self {
+            address,
+            priority,
+            token,
+            registry: registry.try_clone()?,
+            io: Mutex::new(Io { stream, connected: false, outgoing: vec!(), writable_interest: true }),
+            reader: Mutex::new(MessageReader::new(Origin::Server, config.max_array_bytes)),
+            server_minor_version: Mutex::new(None),
+            handlers: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashSet::new()),
+            closed: AtomicBool::new(false),
+            backlog: AtomicUsize::new(0),
+            flow_control: Mutex::new(FlowControl::default()),
+            flow_control_high: config.flow_control_high,
+            flow_control_low: config.flow_control_low,
+            conn_tmo: config.conn_tmo,
+            last_received: Mutex::new(Instant::now()),
+            echo_sent: Mutex::new(None),
+        });
+
+        // CA_PROTO_VERSION must be the first message on a new circuit
+        let mut handshake = Message::Version { priority, minor_version: crate::MINOR_PROTOCOL_VERSION }.as_bytes();
+        handshake.extend(Message::ClientName { name: user_name() }.as_bytes());
+        handshake.extend(Message::HostName { name: host_name() }.as_bytes());
+        circuit.send_bytes(&handshake)?;
+        info!("Opening virtual circuit to {} (priority {})", address, priority);
+
+        Ok(circuit)
+    }
+
+    /// Address of the server at the other end of the circuit.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Priority the circuit was opened with.
+    pub fn priority(&self) -> u16 {
+        self.priority
+    }
+
+    /// Minor protocol version announced by the server, once its CA_PROTO_VERSION message has arrived.
+    pub fn server_minor_version(&self) -> Option<u16> {
+        *self.server_minor_version.lock().unwrap()
+    }
+
+    /// Returns true once the circuit has been closed or lost.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Encodes and sends a message to the server, buffering whatever the socket cannot take yet.
+    pub fn send(&self, message: &Message) -> Result<(), Error> {
+        self.send_bytes(&message.as_bytes())
+    }
+
+    fn send_bytes(&self, buf: &[u8]) -> Result<(), Error> {
+        if self.is_closed() {
+            return Err(Error::IoError(format!("Virtual circuit to {} is closed", self.address)))
+        }
+        let mut io = self.io.lock().unwrap();
+        io.outgoing.extend_from_slice(buf);
+        self.flush(&mut io)
+    }
+
+    /// Writes as much of the outgoing buffer as the socket accepts, waiting for writability while data is left over.
+    fn flush(&self, io: &mut Io) -> Result<(), Error> {
+        if !io.connected {
+            return Ok(())
+        }
+        while !io.outgoing.is_empty() {
+            match io.stream.write(&io.outgoing) {
+                Ok(0) => return Err(Error::IoError(format!("Virtual circuit to {} stopped accepting data", self.address))),
+                Ok(amt) => { io.outgoing.drain(..amt); },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let writable_interest = !io.outgoing.is_empty();
+        if writable_interest != io.writable_interest {
+            let interest = if writable_interest { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+            self.registry.reregister(&mut io.stream, self.token, interest)?;
+            io.writable_interest = writable_interest;
+        }
+        Ok(())
+    }
+
+    /// Routes messages carrying `id` (a channel ID, IOID or subscription ID) to `handler`.
+    pub fn register(&self, id: u32, handler: Handler) {
+        self.handlers.lock().unwrap().insert(id, handler);
+    }
+
+    /// Stops routing messages carrying `id`.
+    pub fn unregister(&self, id: u32) {
+        self.handlers.lock().unwrap().remove(&id);
+    }
+
+    /// Registers a channel on the circuit, keeping the circuit open while it is attached.
+    pub fn attach_channel(&self, cid: u32, handler: Handler) {
+        self.channels.lock().unwrap().insert(cid);
+        self.register(cid, handler);
+    }
+
+    /// Removes a channel from the circuit, closing the circuit if it was the last one.
+    pub fn detach_channel(&self, cid: u32) {
+        self.unregister(cid);
+        let mut channels = self.channels.lock().unwrap();
+        channels.remove(&cid);
+        if channels.is_empty() {
+            debug!("Last channel on circuit to {} cleared", self.address);
+            drop(channels);
+            self.close();
+        }
+    }
+
+    /// Number of channels attached to the circuit.
+    pub fn channel_count(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    /// Number of subscription updates received on the circuit that are still waiting to be read.
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::SeqCst)
+    }
+
+    /// Returns true while the server has been asked to stop sending subscription updates.
+    pub fn events_paused(&self) -> bool {
+        self.flow_control.lock().unwrap().events_off
+    }
+
+    /// Asks the server to stop sending subscription updates until [`Circuit::resume_events`] is called.
+    pub fn pause_events(&self) -> Result<(), Error> {
+        let mut flow_control = self.flow_control.lock().unwrap();
+        flow_control.paused = true;
+        self.set_events_off(&mut flow_control, true)
+    }
+
+    /// Asks the server to resume subscription updates, unless the backlog is still above the low watermark.
+    pub fn resume_events(&self) -> Result<(), Error> {
+        let mut flow_control = self.flow_control.lock().unwrap();
+        flow_control.paused = false;
+        if self.flow_control_high == 0 || self.backlog() <= self.flow_control_low {
+            self.set_events_off(&mut flow_control, false)?;
+        }
+        Ok(())
+    }
+
+    /// Records a subscription update queued for the application, pausing updates at the high watermark.
+    pub(crate) fn update_queued(&self) {
+        let backlog = self.backlog.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.flow_control_high > 0 && backlog >= self.flow_control_high {
+            let mut flow_control = self.flow_control.lock().unwrap();
+            if !flow_control.events_off {
+                debug!("{} unread updates on circuit to {}, pausing events", backlog, self.address);
+                self.set_events_off(&mut flow_control, true).unwrap_or_else(|e| debug!("Could not pause events: {:?}", e));
+            }
+        }
+    }
+
+    /// Records a queued subscription update read by the application, resuming updates at the low watermark.
+    pub(crate) fn update_consumed(&self) {
+        let backlog = self.backlog.fetch_sub(1, Ordering::SeqCst) - 1;
+        if self.flow_control_high > 0 && backlog <= self.flow_control_low {
+            let mut flow_control = self.flow_control.lock().unwrap();
+            if flow_control.events_off && !flow_control.paused {
+                debug!("Backlog on circuit to {} down to {}, resuming events", self.address, backlog);
+                self.set_events_off(&mut flow_control, false).unwrap_or_else(|e| debug!("Could not resume events: {:?}", e));
+            }
+        }
+    }
+
+    /// Sends CA_PROTO_EVENTS_OFF or CA_PROTO_EVENTS_ON if the server is not already in that state.
+    fn set_events_off(&self, flow_control: &mut FlowControl, events_off: bool) -> Result<(), Error> {
+        if flow_control.events_off != events_off {
+            self.send(if events_off { &Message::EventsOff } else { &Message::EventsOn })?;
+            flow_control.events_off = events_off;
+        }
+        Ok(())
+    }
+
+    /// Verifies the connection: sends CA_PROTO_ECHO after EPICS_CA_CONN_TMO of silence, and closes the circuit
+    /// if nothing is received within 5 seconds (or the connection timeout, if shorter) of sending it.
+    pub(crate) fn check_responsive(&self, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+
+        let mut echo_sent = self.echo_sent.lock().unwrap();
+        match *echo_sent {
+            Some(sent) => if now - sent >= self.conn_tmo.min(Duration::from_secs_f64(ECHO_TIMEOUT)) {
+                warn!("Virtual circuit to {} is unresponsive", self.address);
+                drop(echo_sent);
+                self.close();
+            },
+            None => if now - *self.last_received.lock().unwrap() >= self.conn_tmo {
+                trace!("Verifying silent circuit to {}", self.address);
+                match self.send(&Message::Echo) {
+                    Ok(()) => *echo_sent = Some(now),
+                    Err(e) => debug!("Could not send echo to {}: {:?}", self.address, e),
+                }
+            },
+        }
+    }
+
+    /// Notes that the server is alive.
+    fn received(&self) {
+        *self.last_received.lock().unwrap() = Instant::now();
+        *self.echo_sent.lock().unwrap() = None;
+    }
+
+    /// Shuts the connection down. Handlers still registered are notified that the circuit was lost.
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            info!("Closing virtual circuit to {}", self.address);
+            if let Err(e) = self.io.lock().unwrap().stream.shutdown(Shutdown::Both) {
+                trace!("Could not shut down circuit to {}: {:?}", self.address, e);
+            }
+        }
+    }
+
+    /// Token the circuit's socket is registered under.
+    pub(crate) fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Handles a readiness event from the event loop, returning false once the connection has ended.
+    pub(crate) fn ready(&self, event: &Event) -> bool {
+        if event.is_writable() && !self.writable() {
+            return false
+        }
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            return self.readable()
+        }
+        true
+    }
+
+    /// Completes the connection once the socket is writable and flushes the outgoing buffer.
+    fn writable(&self) -> bool {
+        let mut io = self.io.lock().unwrap();
+        if !io.connected {
+            // A writable socket has finished connecting, successfully or not
+            if let Some(e) = io.stream.take_error().unwrap_or_else(Some) {
+                warn!("Could not connect to {}: {:?}", self.address, e);
+                return false
+            }
+            match io.stream.peer_addr() {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotConnected => return true,
+                Err(e) => {
+                    warn!("Could not connect to {}: {:?}", self.address, e);
+                    return false
+                }
+            }
+            io.connected = true;
+            if let Err(e) = io.stream.set_nodelay(true) {
+                debug!("Could not disable Nagle's algorithm on circuit to {}: {:?}", self.address, e);
+            }
+            info!("Opened virtual circuit to {} (priority {})", self.address, self.priority);
+        }
+        match self.flush(&mut io) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Virtual circuit to {} lost: {:?}", self.address, e);
+                false
+            }
+        }
+    }
+
+    /// Reads everything available on the socket and dispatches the complete messages.
+    fn readable(&self) -> bool {
+        let mut buf = [0u8; 16384];
+        let mut messages = vec!();
+        let alive = loop {
+            let result = self.io.lock().unwrap().stream.read(&mut buf);
+            match result {
+                Ok(0) => {
+                    if !self.is_closed() {
+                        warn!("Virtual circuit to {} closed by the server", self.address);
+                    }
+                    break false
+                },
+                Ok(amt) => {
+                    self.received();
+                    match self.reader.lock().unwrap().push(&buf[..amt]) {
+                        Ok(received) => messages.extend(received),
+                        Err(e) => {
+                            warn!("Virtual circuit to {} lost: {:?}", self.address, e);
+                            break false
+                        }
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if !self.is_closed() {
+                        warn!("Virtual circuit to {} lost: {:?}", self.address, e);
+                    }
+                    break false
+                },
+            }
+        };
+
+        // Dispatch unlocked so handlers can send on the circuit
+        for message in messages {
+            self.dispatch(message);
+        }
+        alive
+    }
+
+    /// Deregisters the ended connection and notifies the handlers still registered that the circuit was lost.
+    pub(crate) fn finish(&self) {
+        self.close();
+        if let Err(e) = self.registry.deregister(&mut self.io.lock().unwrap().stream) {
+            trace!("Could not deregister circuit to {}: {:?}", self.address, e);
+        }
+        let handlers: Vec<Handler> = self.handlers.lock().unwrap().drain().map(|(_, handler)| handler).collect();
+        for handler in handlers {
+            handler(CircuitEvent::Disconnected);
+        }
+    }
+
+    /// Hands a received message to the handler registered for the ID it carries.
+    fn dispatch(&self, message: Message) {
+        trace!("Received {:?} from {}", message.command(), self.address);
+        if let Message::Version { minor_version, .. } = message {
+            debug!("Server {} speaks CA minor version {}", self.address, minor_version);
+            *self.server_minor_version.lock().unwrap() = Some(minor_version);
+            return;
+        }
+        if let Message::Echo = message {
+            return;
+        }
+
+        let id = match routing_id(&message) {
+            Some(id) => id,
+            None => {
+                debug!("Ignoring unroutable {:?} from {}", message.command(), self.address);
+                return;
+            }
+        };
+        // Clone the handler out so it can register or unregister IDs without deadlocking
+        let handler = self.handlers.lock().unwrap().get(&id).cloned();
+        match handler {
+            Some(handler) => handler(CircuitEvent::Message(message)),
+            None => debug!("No handler for ID {} on circuit to {}", id, self.address),
+        }
+    }
+}
+
+/// Returns the channel ID, IOID or subscription ID a server message is addressed to.
+fn routing_id(message: &Message) -> Option<u32> {
+    match message {
+        Message::CreateChanResponse { cid, .. }
+        | Message::CreateChFail { cid }
+        | Message::AccessRights { cid, .. }
+        | Message::ServerDisconn { cid }
+        | Message::ClearChannel { cid, .. } => Some(*cid),
+        Message::ReadNotifyResponse { ioid, .. }
+        | Message::WriteNotifyResponse { ioid, .. }
+        | Message::Read { ioid, .. } => Some(*ioid),
+        Message::EventAddResponse { subscription_id, .. } => Some(*subscription_id),
+        _ => None,
+    }
+}
+
+/// Name of the local user, sent with CA_PROTO_CLIENT_NAME.
+fn user_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+/// Name of the local host, sent with CA_PROTO_HOST_NAME.
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".into())
+}
+
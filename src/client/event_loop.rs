@@ -0,0 +1,247 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Interest, Poll, Token};
+use mio::net::UdpSocket;
+
+use crate::protocol::{Message, Origin};
+use super::{Circuit, Error, SearchResult, Shared, MAX_UDP_SIZE, SEARCH_MAX_PERIOD, UPDATE_PERIOD};
+
+use log::{debug, error, info, trace, warn};
+
+/// Token of the waker interrupting the loop when work is added from another thread.
+pub(crate) const WAKER: Token = Token(usize::MAX - 1);
+/// Token of the socket registered with the repeater. Circuits use tokens derived from client IDs.
+const REPEATER: Token = Token(usize::MAX - 2);
+/// Token of the socket sending searches and receiving their replies.
+const SEARCH: Token = Token(usize::MAX - 3);
+
+/// Readiness-driven loop serving every socket of a client on one thread.
+///
+/// It receives beacons and search replies, reads and writes circuits, repeats pending searches and runs the
+/// periodic checks. Other threads hand it work through [`Shared`] and its waker.
+pub(crate) struct EventLoop {
+    poll: Poll,
+    shared: Arc<Shared>,
+    repeater_socket: UdpSocket,
+    search_socket: UdpSocket,
+}
+
+impl EventLoop {
+    /// Registers the repeater and search sockets with `poll`.
+    pub(crate) fn new(poll: Poll, shared: Arc<Shared>, repeater_socket: std::net::UdpSocket, search_socket: std::net::UdpSocket) -> Result<Self, Error> {
+        repeater_socket.set_nonblocking(true)?;
+        search_socket.set_nonblocking(true)?;
+        let mut repeater_socket = UdpSocket::from_std(repeater_socket);
+        let mut search_socket = UdpSocket::from_std(search_socket);
+        poll.registry().register(&mut repeater_socket, REPEATER, Interest::READABLE)?;
+        poll.registry().register(&mut search_socket, SEARCH, Interest::READABLE)?;
+
+        Ok(Self { poll, shared, repeater_socket, search_socket })
+    }
+
+    /// Runs the loop on a new thread until the client stops it.
+    pub(crate) fn spawn(mut self) {
+        std::thread::spawn(move || {
+            self.run();
+            self.shutdown();
+        });
+    }
+
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(256);
+        let mut next_update = Instant::now();
+
+        while !self.shared.stopped.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= next_update {
+                self.update(now);
+                next_update = now + Duration::from_secs_f64(UPDATE_PERIOD);
+            }
+            let next_search = self.send_searches(now);
+
+            let timeout = next_search.map_or(next_update, |next| next.min(next_update)).saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Event loop failed: {:?}", e);
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => (),
+                    REPEATER => self.receive_repeater(),
+                    SEARCH => self.receive_search_replies(),
+                    token => {
+                        let circuit = self.shared.tokens.lock().unwrap().get(&token).cloned();
+                        if let Some(circuit) = circuit {
+                            if !circuit.ready(event) {
+                                self.finish(&circuit);
+                            }
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /// Closes every circuit once the loop has been stopped.
+    fn shutdown(&mut self) {
+        debug!("Event loop stopped");
+        let circuits: Vec<Arc<Circuit>> = self.shared.tokens.lock().unwrap().values().cloned().collect();
+        for circuit in circuits {
+            self.finish(&circuit);
+        }
+    }
+
+    /// Runs the periodic tasks: repeater registration, circuit verification and server expiry.
+    fn update(&mut self, now: Instant) {
+        // Repeat the registration until the repeater confirms it, in case it was not up yet
+        if !self.shared.registered.load(Ordering::SeqCst) {
+            let repeater = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.shared.config.repeater_port);
+            match self.repeater_socket.send_to(&Message::RepeaterRegister { address: crate::LOCALHOST_U32 }.as_bytes(), repeater) {
+                Ok(_) => debug!("Registration message sent"),
+                Err(e) => warn!("Could not send registration packet: {:?}", e),
+            }
+        }
+
+        // Forget circuits that have been closed or lost and verify the silent ones
+        self.shared.circuits.lock().unwrap().retain(|_, circuit| !circuit.is_closed());
+        let circuits: Vec<Arc<Circuit>> = self.shared.tokens.lock().unwrap().values().cloned().collect();
+        for circuit in circuits {
+            if circuit.is_closed() {
+                self.finish(&circuit);
+            } else {
+                circuit.check_responsive(now);
+            }
+        }
+
+        // Remove expired server records
+        self.shared.server_list.lock().unwrap().expire(now, self.shared.config.beacon_period * 2);
+    }
+
+    /// Deregisters an ended circuit and notifies its handlers, once.
+    fn finish(&self, circuit: &Arc<Circuit>) {
+        if self.shared.tokens.lock().unwrap().remove(&circuit.token()).is_some() {
+            circuit.finish();
+        }
+    }
+
+    /// Sends the searches that are due, packed into as few datagrams as possible, and returns when the next one is due.
+    fn send_searches(&mut self, now: Instant) -> Option<Instant> {
+        let mut datagrams = vec!();
+        let mut next_send: Option<Instant> = None;
+        {
+            let version = Message::Version { priority: 0, minor_version: crate::MINOR_PROTOCOL_VERSION }.as_bytes();
+            let mut datagram = version.clone();
+            for search in self.shared.pending_searches.lock().unwrap().values_mut() {
+                if search.next_send <= now {
+                    if datagram.len() + search.request.len() > MAX_UDP_SIZE && datagram.len() > version.len() {
+                        datagrams.push(std::mem::replace(&mut datagram, version.clone()));
+                    }
+                    datagram.extend_from_slice(&search.request);
+                    search.next_send = now + search.period;
+                    search.period = (search.period * 2).min(Duration::from_secs_f64(SEARCH_MAX_PERIOD));
+                }
+                next_send = Some(next_send.map_or(search.next_send, |next| next.min(search.next_send)));
+            }
+            if datagram.len() > version.len() {
+                datagrams.push(datagram);
+            }
+        }
+
+        let addresses = self.shared.search_addresses.lock().unwrap().clone();
+        for datagram in &datagrams {
+            for address in &addresses {
+                // A search lost to a full socket buffer is repeated after its next backoff period
+                if let Err(e) = self.search_socket.send_to(datagram, *address) {
+                    warn!("Could not send search request to {}: {:?}", address, e);
+                }
+            }
+        }
+        next_send
+    }
+
+    /// Hands every search reply received to the matching pending search.
+    fn receive_search_replies(&mut self) {
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        loop {
+            let (amt, src) = match self.search_socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Could not receive search reply: {:?}", e);
+                    return;
+                }
+            };
+            let messages = match Message::all_from_bytes(&buf[..amt], Origin::Server) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    warn!("Could not parse search reply from {}: {:?}", src, e);
+                    continue;
+                }
+            };
+            for message in messages {
+                if let Message::SearchResponse { port, server_ip, cid, minor_version } = message {
+                    // An address of 0xFFFFFFFF means the server is reachable at the reply's source address
+                    let ip = if server_ip == u32::MAX { src.ip() } else { IpAddr::V4(Ipv4Addr::from(server_ip)) };
+                    let result = SearchResult { address: SocketAddr::new(ip, port), minor_version };
+
+                    // Take the search out so its handler runs unlocked and only once
+                    let search = self.shared.pending_searches.lock().unwrap().remove(&cid);
+                    match search {
+                        Some(search) => {
+                            debug!("Search for cid {} answered by {}", cid, result.address);
+                            (search.handler)(result);
+                        },
+                        None => trace!("Ignoring search reply for unknown cid {}", cid),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles every registration confirmation and beacon forwarded by the repeater.
+    fn receive_repeater(&mut self) {
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        loop {
+            let amt = match self.repeater_socket.recv_from(&mut buf) {
+                Ok((amt, _)) => amt,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Typically the repeater's port refusing an early registration
+                    debug!("Could not receive from repeater: {:?}", e);
+                    return;
+                }
+            };
+            match Message::from_bytes(&buf[..amt], Origin::Server) {
+                Ok((Message::RepeaterConfirm { .. }, _)) => {
+                    if !self.shared.registered.swap(true, Ordering::SeqCst) {
+                        info!("Received registration confirmation from repeater");
+                    }
+                },
+                Ok((Message::RsrvIsUp { minor_version, port, beacon_id, address }, _)) => {
+                    let tcp_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(address)), port);
+                    trace!("Received beacon {} from {}", beacon_id, tcp_address);
+
+                    let anomaly = self.shared.server_list.lock().unwrap().beacon(tcp_address, minor_version, beacon_id, Instant::now());
+
+                    // Like libca, search again promptly for unresolved channels when a server appears or changes
+                    if let Some(anomaly) = anomaly {
+                        debug!("Beacon anomaly from {}: {:?}", tcp_address, anomaly);
+                        self.shared.reset_searches();
+                    }
+                },
+                Ok(_) => warn!("Client received unsupported message command"),
+                Err(e) => error!("Error receiving UDP packet: {:?}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+use crate::dbr::Dbr;
+use super::{Circuit, Error};
+use super::channel::ChannelInner;
+
+/// Callback receiving the updates of a subscription.
+pub type Callback = Arc<dyn Fn(Result<Dbr, Error>) + Send + Sync>;
+
+/// Queued update, tagged with the circuit whose backlog it counts towards.
+pub(crate) type Update = (Weak<Circuit>, Result<Dbr, Error>);
+
+/// How the updates of a subscription reach the application.
+#[derive(Clone)]
+pub(crate) enum Delivery {
+    /// Called directly on the client's event loop thread.
+    Callback(Callback),
+    /// Queued for a [`Monitor`], counting towards the circuit's flow control backlog.
+    Queue(Arc<Mutex<Sender<Update>>>),
+    /// Queued for an [`AsyncMonitor`](super::AsyncMonitor), counting towards the circuit's flow control backlog.
+    #[cfg(feature = "tokio")]
+    AsyncQueue(tokio::sync::mpsc::UnboundedSender<Update>),
+}
+
+impl Delivery {
+    pub(crate) fn deliver(&self, circuit: &Weak<Circuit>, update: Result<Dbr, Error>) {
+        match self {
+            Delivery::Callback(callback) => callback(update),
+            Delivery::Queue(sender) => queue(circuit, update, |update| sender.lock().unwrap().send(update).is_ok()),
+            #[cfg(feature = "tokio")]
+            Delivery::AsyncQueue(sender) => queue(circuit, update, |update| sender.send(update).is_ok()),
+        }
+    }
+}
+
+/// Queues an update with `send`, counting it towards the circuit's backlog unless the reader is gone.
+fn queue<F>(circuit: &Weak<Circuit>, update: Result<Dbr, Error>, send: F)
+where F: FnOnce(Update) -> bool {
+    // Count the update before queueing it so the reader cannot take it off the backlog first
+    let circuit = circuit.upgrade();
+    if let Some(circuit) = &circuit {
+        circuit.update_queued();
+    }
+    let weak = circuit.as_ref().map(Arc::downgrade).unwrap_or_default();
+    if !send((weak, update)) {
+        if let Some(circuit) = &circuit {
+            circuit.update_consumed();
+        }
+    }
+}
+
+/// Handle to a CA_PROTO_EVENT_ADD subscription on a channel.
+///
+/// The subscription is restored automatically when its channel reconnects.
+/// Dropping the handle sends CA_PROTO_EVENT_CANCEL and stops delivering updates.
+pub struct Subscription {
+    channel: Arc<ChannelInner>,
+    id: u32,
+}
+
+impl Subscription {
+    pub(crate) fn new(channel: Arc<ChannelInner>, id: u32) -> Self {
+        Self { channel, id }
+    }
+
+    /// Client-assigned subscription ID.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Stops the updates, then takes those still queued in `updates` off their circuit's backlog.
+    ///
+    /// Cancelling first ensures no update is delivered after the queue was drained and left behind on the backlog.
+    pub(crate) fn cancel_and_drain(&self, updates: impl Iterator<Item = Update>) {
+        self.channel.cancel(self.id);
+        for update in updates {
+            let _ = consumed(update);
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.channel.cancel(self.id);
+    }
+}
+
+/// Subscription delivering its updates through a queue.
+///
+/// Iterating over the monitor blocks for each update and ends once the channel is cleared.
+pub struct Monitor {
+    subscription: Subscription,
+    receiver: Receiver<Update>,
+}
+
+impl Monitor {
+    pub(crate) fn new(subscription: Subscription, receiver: Receiver<Update>) -> Self {
+        Self { subscription, receiver }
+    }
+
+    /// Client-assigned subscription ID.
+    pub fn id(&self) -> u32 {
+        self.subscription.id()
+    }
+
+    /// Blocks until the next update arrives. Returns `None` once the channel has been cleared.
+    pub fn recv(&self) -> Option<Result<Dbr, Error>> {
+        self.receiver.recv().ok().map(consumed)
+    }
+
+    /// Returns the next queued update without blocking.
+    pub fn try_recv(&self) -> Option<Result<Dbr, Error>> {
+        match self.receiver.try_recv() {
+            Ok(update) => Some(consumed(update)),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Waits up to `timeout` for the next update.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Result<Dbr, Error>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(update) => Some(consumed(update)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.subscription.cancel_and_drain(self.receiver.try_iter());
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = Result<Dbr, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+/// Takes a queued update off its circuit's backlog.
+pub(crate) fn consumed((circuit, update): Update) -> Result<Dbr, Error> {
+    if let Some(circuit) = circuit.upgrade() {
+        circuit.update_consumed();
+    }
+    update
+}
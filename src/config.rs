@@ -0,0 +1,355 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use log::warn;
+
+/// Default value of EPICS_CA_CONN_TMO in seconds.
+const DEFAULT_CONN_TMO: f64 = 30.0;
+/// Default time in seconds to wait for a reply to a search.
+const DEFAULT_SEARCH_TIMEOUT: f64 = 5.0;
+/// Default value of EPICS_CA_BEACON_PERIOD in seconds.
+const DEFAULT_BEACON_PERIOD: f64 = 15.0;
+/// Default value of EPICS_CA_MAX_ARRAY_BYTES.
+const DEFAULT_MAX_ARRAY_BYTES: usize = 16384;
+/// Default number of queued monitor updates on a circuit at which the server is asked to pause updates.
+const DEFAULT_FLOW_CONTROL_HIGH: usize = 1000;
+/// Default number of queued monitor updates on a circuit at which the server is asked to resume updates.
+const DEFAULT_FLOW_CONTROL_LOW: usize = 100;
+/// Default number of updates a server queues per subscription before coalescing them.
+const DEFAULT_EVENT_QUEUE_SIZE: usize = 8;
+
+/// Settings of a [`Client`](crate::Client): where it searches, how long it waits and how much it buffers.
+///
+/// [`ClientConfig::from_env`] fills in the fields that have an EPICS_CA_* variable; the others are only
+/// set through the builder-style method of the same name, which every field has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    /// EPICS_CA_ADDR_LIST: additional addresses search requests are sent to.
+    pub addr_list: Vec<SocketAddr>,
+
+    /// EPICS_CA_AUTO_ADDR_LIST: whether the broadcast address is added to the search addresses.
+    pub auto_addr_list: bool,
+
+    /// EPICS_CA_SERVER_PORT: default port of servers, used for search requests and address list entries without a port.
+    pub server_port: u16,
+
+    /// EPICS_CA_REPEATER_PORT: port of the local repeater.
+    pub repeater_port: u16,
+
+    /// EPICS_CA_CONN_TMO: period of silence after which a connection is verified.
+    pub conn_tmo: Duration,
+
+    /// Time [`Client::search`](crate::Client::search) waits for a reply. Builder-only, there is no variable for it.
+    pub search_timeout: Duration,
+
+    /// EPICS_CA_BEACON_PERIOD: expected period between server beacons. Servers silent for twice as long are forgotten.
+    pub beacon_period: Duration,
+
+    /// EPICS_CA_MAX_ARRAY_BYTES: largest payload the client accepts.
+    pub max_array_bytes: usize,
+
+    /// Backlog of unread monitor updates on a circuit at which CA_PROTO_EVENTS_OFF is sent. Zero disables flow control.
+    /// Builder-only, there is no variable for it.
+    pub flow_control_high: usize,
+
+    /// Backlog of unread monitor updates on a circuit at which CA_PROTO_EVENTS_ON is sent again.
+    /// Builder-only, there is no variable for it.
+    pub flow_control_low: usize,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            addr_list: vec!(),
+            auto_addr_list: true,
+            server_port: crate::CA_SERVER_PORT,
+            repeater_port: crate::CA_REPEATER_PORT,
+            conn_tmo: Duration::from_secs_f64(DEFAULT_CONN_TMO),
+            search_timeout: Duration::from_secs_f64(DEFAULT_SEARCH_TIMEOUT),
+            beacon_period: Duration::from_secs_f64(DEFAULT_BEACON_PERIOD),
+            max_array_bytes: DEFAULT_MAX_ARRAY_BYTES,
+            flow_control_high: DEFAULT_FLOW_CONTROL_HIGH,
+            flow_control_low: DEFAULT_FLOW_CONTROL_LOW,
+        }
+    }
+}
+impl ClientConfig {
+    /// Reads the EPICS_CA_* client variables. Unset or unparsable variables, and the builder-only fields,
+    /// keep their [`Default`] values.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let server_port = env_parse("EPICS_CA_SERVER_PORT").unwrap_or(defaults.server_port);
+
+        Self {
+            addr_list: std::env::var("EPICS_CA_ADDR_LIST")
+                .map(|list| parse_addr_list(&list, server_port))
+                .unwrap_or_default(),
+            auto_addr_list: env_bool("EPICS_CA_AUTO_ADDR_LIST").unwrap_or(defaults.auto_addr_list),
+            server_port,
+            repeater_port: env_parse("EPICS_CA_REPEATER_PORT").unwrap_or(defaults.repeater_port),
+            conn_tmo: env_secs("EPICS_CA_CONN_TMO").unwrap_or(defaults.conn_tmo),
+            beacon_period: env_secs("EPICS_CA_BEACON_PERIOD").unwrap_or(defaults.beacon_period),
+            max_array_bytes: env_parse("EPICS_CA_MAX_ARRAY_BYTES").unwrap_or(defaults.max_array_bytes),
+            ..defaults
+        }
+    }
+
+    pub fn addr_list(mut self, addr_list: Vec<SocketAddr>) -> Self {
+        self.addr_list = addr_list;
+        self
+    }
+
+    pub fn auto_addr_list(mut self, auto_addr_list: bool) -> Self {
+        self.auto_addr_list = auto_addr_list;
+        self
+    }
+
+    pub fn server_port(mut self, server_port: u16) -> Self {
+        self.server_port = server_port;
+        self
+    }
+
+    pub fn repeater_port(mut self, repeater_port: u16) -> Self {
+        self.repeater_port = repeater_port;
+        self
+    }
+
+    pub fn conn_tmo(mut self, conn_tmo: Duration) -> Self {
+        self.conn_tmo = conn_tmo;
+        self
+    }
+
+    pub fn search_timeout(mut self, search_timeout: Duration) -> Self {
+        self.search_timeout = search_timeout;
+        self
+    }
+
+    pub fn beacon_period(mut self, beacon_period: Duration) -> Self {
+        self.beacon_period = beacon_period;
+        self
+    }
+
+    pub fn max_array_bytes(mut self, max_array_bytes: usize) -> Self {
+        self.max_array_bytes = max_array_bytes;
+        self
+    }
+
+    pub fn flow_control_high(mut self, flow_control_high: usize) -> Self {
+        self.flow_control_high = flow_control_high;
+        self
+    }
+
+    pub fn flow_control_low(mut self, flow_control_low: usize) -> Self {
+        self.flow_control_low = flow_control_low;
+        self
+    }
+
+    /// Returns every address search requests should be sent to.
+    pub fn search_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = self.addr_list.clone();
+        if self.auto_addr_list {
+            addresses.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), self.server_port));
+        }
+        addresses
+    }
+}
+
+/// Settings of a [`Server`](crate::Server): the interface and ports it binds, where its beacons go and
+/// how many updates it holds back per subscription.
+///
+/// Most fields have an EPICS_CAS_* variable that falls back to its EPICS_CA_* counterpart, as in the EPICS
+/// server. Each field also has a builder-style method of the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// EPICS_CAS_INTF_ADDR_LIST: interface the server listens on. Only the first entry is used.
+    pub interface: IpAddr,
+
+    /// EPICS_CAS_SERVER_PORT, or EPICS_CA_SERVER_PORT when unset: port searches are received on.
+    /// Circuits are accepted on the same port when it is free, on any port otherwise.
+    pub server_port: u16,
+
+    /// EPICS_CA_MAX_ARRAY_BYTES: largest payload the server accepts or sends.
+    pub max_array_bytes: usize,
+
+    /// EPICS_CAS_BEACON_ADDR_LIST, or EPICS_CA_ADDR_LIST when unset: additional addresses beacons are sent to.
+    pub beacon_addr_list: Vec<SocketAddr>,
+
+    /// EPICS_CAS_AUTO_BEACON_ADDR_LIST, or EPICS_CA_AUTO_ADDR_LIST when unset: whether beacons are also broadcast.
+    pub auto_beacon_addr_list: bool,
+
+    /// EPICS_CAS_BEACON_PORT, or EPICS_CA_REPEATER_PORT when unset: port beacons are broadcast to
+    /// and that address list entries without a port default to.
+    pub beacon_port: u16,
+
+    /// EPICS_CAS_BEACON_PERIOD, or EPICS_CA_BEACON_PERIOD when unset: period beacons settle at after the fast start.
+    /// Periods shorter than the 20 ms the fast start begins with are raised to it.
+    pub beacon_period: Duration,
+
+    /// Updates queued per subscription while the client is slow or has turned events off. Once the queue is full,
+    /// the newest queued update is replaced by later ones so that the latest value is always delivered.
+    /// Builder-only, there is no variable for it.
+    pub event_queue_size: usize,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            interface: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            server_port: crate::CA_SERVER_PORT,
+            max_array_bytes: DEFAULT_MAX_ARRAY_BYTES,
+            beacon_addr_list: vec!(),
+            auto_beacon_addr_list: true,
+            beacon_port: crate::CA_REPEATER_PORT,
+            beacon_period: Duration::from_secs_f64(crate::CA_SERVER_BEACON_MAX_PERIOD),
+            event_queue_size: DEFAULT_EVENT_QUEUE_SIZE,
+        }
+    }
+}
+impl ServerConfig {
+    /// Reads each EPICS_CAS_* server variable, or its EPICS_CA_* counterpart when unset. Fields whose variables
+    /// are unset or unparsable keep their [`Default`] values, as does `event_queue_size`.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let server_port = env_parse("EPICS_CAS_SERVER_PORT")
+            .or_else(|| env_parse("EPICS_CA_SERVER_PORT"))
+            .unwrap_or(defaults.server_port);
+        let beacon_port = env_parse("EPICS_CAS_BEACON_PORT")
+            .or_else(|| env_parse("EPICS_CA_REPEATER_PORT"))
+            .unwrap_or(defaults.beacon_port);
+
+        Self {
+            interface: std::env::var("EPICS_CAS_INTF_ADDR_LIST").ok()
+                .and_then(|list| parse_addr_list(&list, server_port).first().map(SocketAddr::ip))
+                .unwrap_or(defaults.interface),
+            server_port,
+            max_array_bytes: env_parse("EPICS_CA_MAX_ARRAY_BYTES").unwrap_or(defaults.max_array_bytes),
+            beacon_addr_list: std::env::var("EPICS_CAS_BEACON_ADDR_LIST")
+                .or_else(|_| std::env::var("EPICS_CA_ADDR_LIST"))
+                .map(|list| parse_addr_list(&list, beacon_port))
+                .unwrap_or_default(),
+            auto_beacon_addr_list: env_bool("EPICS_CAS_AUTO_BEACON_ADDR_LIST")
+                .or_else(|| env_bool("EPICS_CA_AUTO_ADDR_LIST"))
+                .unwrap_or(defaults.auto_beacon_addr_list),
+            beacon_port,
+            beacon_period: env_secs("EPICS_CAS_BEACON_PERIOD")
+                .or_else(|| env_secs("EPICS_CA_BEACON_PERIOD"))
+                .unwrap_or(defaults.beacon_period),
+            ..defaults
+        }
+    }
+
+    pub fn interface(mut self, interface: IpAddr) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    pub fn server_port(mut self, server_port: u16) -> Self {
+        self.server_port = server_port;
+        self
+    }
+
+    pub fn max_array_bytes(mut self, max_array_bytes: usize) -> Self {
+        self.max_array_bytes = max_array_bytes;
+        self
+    }
+
+    pub fn beacon_addr_list(mut self, beacon_addr_list: Vec<SocketAddr>) -> Self {
+        self.beacon_addr_list = beacon_addr_list;
+        self
+    }
+
+    pub fn auto_beacon_addr_list(mut self, auto_beacon_addr_list: bool) -> Self {
+        self.auto_beacon_addr_list = auto_beacon_addr_list;
+        self
+    }
+
+    pub fn beacon_port(mut self, beacon_port: u16) -> Self {
+        self.beacon_port = beacon_port;
+        self
+    }
+
+    pub fn beacon_period(mut self, beacon_period: Duration) -> Self {
+        self.beacon_period = beacon_period;
+        self
+    }
+
+    pub fn event_queue_size(mut self, event_queue_size: usize) -> Self {
+        self.event_queue_size = event_queue_size;
+        self
+    }
+
+    /// Returns every address beacons should be sent to.
+    pub fn beacon_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = self.beacon_addr_list.clone();
+        if self.auto_beacon_addr_list {
+            addresses.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), self.beacon_port));
+        }
+        addresses
+    }
+}
+
+/// Parses a whitespace separated list of `host[:port]` entries, using `default_port` when no port is given.
+/// Entries that cannot be resolved to an IPv4 address are skipped with a warning.
+pub fn parse_addr_list(list: &str, default_port: u16) -> Vec<SocketAddr> {
+    list.split_whitespace()
+        .filter_map(|entry| {
+            let resolved = if entry.contains(':') {
+                entry.to_socket_addrs()
+            } else {
+                (entry, default_port).to_socket_addrs()
+            };
+            match resolved.map(|mut addrs| addrs.find(SocketAddr::is_ipv4)) {
+                Ok(Some(address)) => Some(address),
+                _ => {
+                    warn!("Ignoring invalid address list entry {:?}", entry);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Reads and parses an environment variable, warning when it is set but invalid.
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("Ignoring invalid value {:?} for {}", value, name);
+            None
+        }
+    }
+}
+
+/// Reads a YES/NO environment variable.
+fn env_bool(name: &str) -> Option<bool> {
+    let value = std::env::var(name).ok()?;
+    match value.trim().to_ascii_uppercase().as_str() {
+        "YES" | "TRUE" | "1" => Some(true),
+        "NO" | "FALSE" | "0" => Some(false),
+        _ => {
+            warn!("Ignoring invalid value {:?} for {}", value, name);
+            None
+        }
+    }
+}
+
+/// Reads an environment variable holding a positive number of seconds.
+fn env_secs(name: &str) -> Option<Duration> {
+    env_parse::<f64>(name)
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_list_parsing() {
+        let list = parse_addr_list(" 10.0.0.255  127.0.0.1:6064\tnot/an/address ", 5064);
+        assert_eq!(list, vec!["10.0.0.255:5064".parse().unwrap(), "127.0.0.1:6064".parse().unwrap()]);
+
+        let config = ClientConfig::default().addr_list(list).server_port(7064);
+        assert_eq!(config.search_addresses().last(), Some(&"255.255.255.255:7064".parse().unwrap()));
+        assert_eq!(config.auto_addr_list(false).search_addresses().len(), 2);
+    }
+}
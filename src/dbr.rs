@@ -0,0 +1,991 @@
+use std::convert::{TryFrom, TryInto};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{DataType, Error};
+
+/// Size of a DBR_STRING element, including the null terminator.
+pub const MAX_STRING_SIZE: usize = 40;
+/// Size of the units field in DBR_GR and DBR_CTRL structures.
+pub const MAX_UNITS_SIZE: usize = 8;
+/// Size of a single enum state string in DBR_GR_ENUM and DBR_CTRL_ENUM structures.
+pub const MAX_ENUM_STRING_SIZE: usize = 26;
+/// Number of enum state strings carried by DBR_GR_ENUM and DBR_CTRL_ENUM structures.
+pub const MAX_ENUM_STATES: usize = 16;
+
+/// Seconds between the UNIX epoch and the EPICS epoch (1990-01-01 00:00:00 UTC).
+pub const EPICS_EPOCH_OFFSET: u64 = 631_152_000;
+
+/// Primitive element type of a DBR payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Short,
+    Float,
+    Enum,
+    Char,
+    Long,
+    Double,
+}
+impl FieldType {
+    /// Size in bytes of a single element on the wire.
+    pub fn element_size(self) -> usize {
+        match self {
+            FieldType::String => MAX_STRING_SIZE,
+            FieldType::Short => 2,
+            FieldType::Float => 4,
+            FieldType::Enum => 2,
+            FieldType::Char => 1,
+            FieldType::Long => 4,
+            FieldType::Double => 8,
+        }
+    }
+
+    fn index(self) -> u16 {
+        match self {
+            FieldType::String => 0,
+            FieldType::Short => 1,
+            FieldType::Float => 2,
+            FieldType::Enum => 3,
+            FieldType::Char => 4,
+            FieldType::Long => 5,
+            FieldType::Double => 6,
+        }
+    }
+
+    fn from_index(index: u16) -> Self {
+        match index {
+            0 => FieldType::String,
+            1 => FieldType::Short,
+            2 => FieldType::Float,
+            3 => FieldType::Enum,
+            4 => FieldType::Char,
+            5 => FieldType::Long,
+            _ => FieldType::Double,
+        }
+    }
+}
+
+/// Metadata structure wrapped around the value of a DBR payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// The bare value.
+    Plain,
+    /// Alarm status and severity.
+    Sts,
+    /// Alarm status, severity and timestamp.
+    Time,
+    /// Alarm status, severity and display information (units, precision, display and alarm limits, enum strings).
+    Gr,
+    /// Everything in DBR_GR plus control limits.
+    Ctrl,
+    /// DBR_PUT_ACKT: acknowledge transient alarms flag.
+    PutAckt,
+    /// DBR_PUT_ACKS: acknowledge alarm severity.
+    PutAcks,
+    /// DBR_STSACK_STRING: alarm state with acknowledgment settings and a string value.
+    StsAckString,
+    /// DBR_CLASS_NAME: record type name.
+    ClassName,
+}
+impl Category {
+    fn offset(self) -> u16 {
+        match self {
+            Category::Plain => 0,
+            Category::Sts => 7,
+            Category::Time => 14,
+            Category::Gr => 21,
+            Category::Ctrl => 28,
+            Category::PutAckt => 35,
+            Category::PutAcks => 36,
+            Category::StsAckString => 37,
+            Category::ClassName => 38,
+        }
+    }
+}
+
+impl DataType {
+    /// Returns the primitive type of the value carried by this data type.
+    pub fn field_type(self) -> FieldType {
+        match self.category() {
+            Category::PutAckt | Category::PutAcks => FieldType::Enum,
+            Category::StsAckString | Category::ClassName => FieldType::String,
+            _ => FieldType::from_index(u16::from(self) % 7),
+        }
+    }
+
+    /// Returns the metadata structure carried by this data type.
+    pub fn category(self) -> Category {
+        match u16::from(self) {
+            0..=6 => Category::Plain,
+            7..=13 => Category::Sts,
+            14..=20 => Category::Time,
+            21..=27 => Category::Gr,
+            28..=34 => Category::Ctrl,
+            35 => Category::PutAckt,
+            36 => Category::PutAcks,
+            37 => Category::StsAckString,
+            _ => Category::ClassName,
+        }
+    }
+
+    /// Builds the data type carrying `category` metadata around a `field_type` value.
+    /// The field type is ignored for the special alarm acknowledgment and class name categories.
+    pub fn from_parts(category: Category, field_type: FieldType) -> Self {
+        let id = match category {
+            Category::PutAckt | Category::PutAcks | Category::StsAckString | Category::ClassName => category.offset(),
+            _ => category.offset() + field_type.index(),
+        };
+        DataType::try_from(id).expect("Every category and field type combination is a valid data type")
+    }
+
+    /// Size in bytes of a payload of this type holding `count` elements, before padding.
+    pub fn payload_size(self, count: usize) -> usize {
+        value_offset(self.category(), self.field_type()) + self.field_type().element_size() * count
+    }
+}
+
+/// Alarm state attached to every DBR_STS, DBR_TIME, DBR_GR and DBR_CTRL value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Alarm {
+    pub status: u16,
+    pub severity: u16,
+}
+
+/// Alarm severities and statuses, as defined by EPICS alarm.h.
+pub mod alarm {
+    /// Values of [`Alarm::severity`](super::Alarm::severity).
+    pub mod severity {
+        pub const NO_ALARM: u16 = 0;
+        pub const MINOR_ALARM: u16 = 1;
+        pub const MAJOR_ALARM: u16 = 2;
+        pub const INVALID_ALARM: u16 = 3;
+    }
+
+    /// Values of [`Alarm::status`](super::Alarm::status) raised by alarm limits.
+    pub mod status {
+        pub const NO_ALARM: u16 = 0;
+        pub const HIHI_ALARM: u16 = 3;
+        pub const HIGH_ALARM: u16 = 4;
+        pub const LOLO_ALARM: u16 = 5;
+        pub const LOW_ALARM: u16 = 6;
+    }
+}
+
+/// EPICS timestamp: seconds and nanoseconds since the EPICS epoch (1990-01-01 00:00:00 UTC).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EpicsTime {
+    pub secs: u32,
+    pub nanos: u32,
+}
+impl EpicsTime {
+    /// Returns the current time as an EPICS timestamp.
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    /// Converts the timestamp into a `SystemTime`.
+    pub fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.secs as u64 + EPICS_EPOCH_OFFSET, self.nanos)
+    }
+}
+impl From<SystemTime> for EpicsTime {
+    fn from(time: SystemTime) -> Self {
+        let since_epics = time
+            .duration_since(UNIX_EPOCH + Duration::from_secs(EPICS_EPOCH_OFFSET))
+            .unwrap_or_default();
+        Self {
+            secs: since_epics.as_secs() as u32,
+            nanos: since_epics.subsec_nanos(),
+        }
+    }
+}
+
+/// Display, alarm, warning and control limits of a DBR_GR or DBR_CTRL structure.
+///
+/// Limits are transferred in the value's native type and widened to f64 here.
+/// Control limits are only transferred by DBR_CTRL structures.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub upper_disp_limit: f64,
+    pub lower_disp_limit: f64,
+    pub upper_alarm_limit: f64,
+    pub upper_warning_limit: f64,
+    pub lower_warning_limit: f64,
+    pub lower_alarm_limit: f64,
+    pub upper_ctrl_limit: f64,
+    pub lower_ctrl_limit: f64,
+}
+
+/// Array of elements of one primitive type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Vec<String>),
+    Short(Vec<i16>),
+    Float(Vec<f32>),
+    Enum(Vec<u16>),
+    Char(Vec<u8>),
+    Long(Vec<i32>),
+    Double(Vec<f64>),
+}
+impl Value {
+    /// Returns the primitive type of the elements.
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            Value::String(_) => FieldType::String,
+            Value::Short(_) => FieldType::Short,
+            Value::Float(_) => FieldType::Float,
+            Value::Enum(_) => FieldType::Enum,
+            Value::Char(_) => FieldType::Char,
+            Value::Long(_) => FieldType::Long,
+            Value::Double(_) => FieldType::Double,
+        }
+    }
+
+    /// Returns the number of elements.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::String(v) => v.len(),
+            Value::Short(v) => v.len(),
+            Value::Float(v) => v.len(),
+            Value::Enum(v) => v.len(),
+            Value::Char(v) => v.len(),
+            Value::Long(v) => v.len(),
+            Value::Double(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Truncates the value to `len` elements, or pads it with zeros or empty strings.
+    pub fn resize(&mut self, len: usize) {
+        match self {
+            Value::String(v) => v.resize(len, String::new()),
+            Value::Short(v) => v.resize(len, 0),
+            Value::Float(v) => v.resize(len, 0.0),
+            Value::Enum(v) => v.resize(len, 0),
+            Value::Char(v) => v.resize(len, 0),
+            Value::Long(v) => v.resize(len, 0),
+            Value::Double(v) => v.resize(len, 0.0),
+        }
+    }
+
+    /// Decodes up to `count` elements of `field_type` from big-endian bytes.
+    ///
+    /// Servers may shorten the last string element, so a truncated trailing string is accepted.
+    pub fn from_bytes(field_type: FieldType, count: usize, buf: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(buf);
+        Ok(match field_type {
+            FieldType::String => {
+                let mut strings = Vec::with_capacity(count);
+                for _ in 0..count {
+                    if reader.remaining() == 0 {
+                        break;
+                    }
+                    let size = reader.remaining().min(MAX_STRING_SIZE);
+                    strings.push(reader.string(size)?);
+                }
+                Value::String(strings)
+            },
+            FieldType::Short => Value::Short((0..count).map(|_| reader.i16()).collect::<Result<_, _>>()?),
+            FieldType::Float => Value::Float((0..count).map(|_| reader.f32()).collect::<Result<_, _>>()?),
+            FieldType::Enum => Value::Enum((0..count).map(|_| reader.u16()).collect::<Result<_, _>>()?),
+            FieldType::Char => Value::Char(reader.bytes(count)?.to_vec()),
+            FieldType::Long => Value::Long((0..count).map(|_| reader.i32()).collect::<Result<_, _>>()?),
+            FieldType::Double => Value::Double((0..count).map(|_| reader.f64()).collect::<Result<_, _>>()?),
+        })
+    }
+
+    /// Encodes the elements as big-endian bytes.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len() * self.field_type().element_size());
+        match self {
+            Value::String(v) => v.iter().for_each(|s| write_string(&mut buf, s, MAX_STRING_SIZE)),
+            Value::Short(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+            Value::Float(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+            Value::Enum(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+            Value::Char(v) => buf.extend_from_slice(v),
+            Value::Long(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+            Value::Double(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+        }
+        buf
+    }
+
+    /// Converts every element to `field_type`, parsing or formatting strings as needed.
+    pub fn convert(self, field_type: FieldType) -> Result<Self, Error> {
+        if self.field_type() == field_type {
+            return Ok(self)
+        }
+        Ok(match field_type {
+            FieldType::String => Value::String(Vec::from_value(self)?),
+            FieldType::Short => Value::Short(Vec::from_value(self)?),
+            FieldType::Float => Value::Float(Vec::from_value(self)?),
+            FieldType::Enum => Value::Enum(Vec::from_value(self)?),
+            FieldType::Char => Value::Char(Vec::from_value(self)?),
+            FieldType::Long => Value::Long(Vec::from_value(self)?),
+            FieldType::Double => Value::Double(Vec::from_value(self)?),
+        })
+    }
+}
+
+/// Conversion from a decoded value into a Rust type, used to read channels.
+pub trait FromValue: Sized {
+    /// Field type requested from the server when reading this type.
+    fn field_type() -> FieldType;
+
+    /// Whether every element should be requested rather than only the first one.
+    fn is_array() -> bool {
+        false
+    }
+
+    fn from_value(value: Value) -> Result<Self, Error>;
+}
+
+macro_rules! impl_from_value {
+    ($t:ty, $field_type:expr) => {
+        impl FromValue for Vec<$t> {
+            fn field_type() -> FieldType {
+                $field_type
+            }
+
+            fn is_array() -> bool {
+                true
+            }
+
+            #[allow(clippy::unnecessary_cast)]
+            fn from_value(value: Value) -> Result<Self, Error> {
+                Ok(match value {
+                    Value::Short(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::Float(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::Enum(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::Char(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::Long(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::Double(v) => v.into_iter().map(|x| x as $t).collect(),
+                    Value::String(v) => v
+                        .iter()
+                        .map(|x| x.trim().parse::<$t>())
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| Error::ParseError(format!("Could not convert string value: {:?}", e)))?,
+                })
+            }
+        }
+
+        impl FromValue for $t {
+            fn field_type() -> FieldType {
+                $field_type
+            }
+
+            fn from_value(value: Value) -> Result<Self, Error> {
+                first(Vec::<$t>::from_value(value)?)
+            }
+        }
+    };
+}
+
+impl_from_value!(i16, FieldType::Short);
+impl_from_value!(f32, FieldType::Float);
+impl_from_value!(u16, FieldType::Enum);
+impl_from_value!(u8, FieldType::Char);
+impl_from_value!(i32, FieldType::Long);
+impl_from_value!(f64, FieldType::Double);
+
+impl FromValue for Vec<String> {
+    fn field_type() -> FieldType {
+        FieldType::String
+    }
+
+    fn is_array() -> bool {
+        true
+    }
+
+    fn from_value(value: Value) -> Result<Self, Error> {
+        Ok(match value {
+            Value::String(v) => v,
+            Value::Short(v) => v.iter().map(ToString::to_string).collect(),
+            Value::Float(v) => v.iter().map(ToString::to_string).collect(),
+            Value::Enum(v) => v.iter().map(ToString::to_string).collect(),
+            Value::Char(v) => v.iter().map(ToString::to_string).collect(),
+            Value::Long(v) => v.iter().map(ToString::to_string).collect(),
+            Value::Double(v) => v.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
+impl FromValue for String {
+    fn field_type() -> FieldType {
+        FieldType::String
+    }
+
+    fn from_value(value: Value) -> Result<Self, Error> {
+        first(Vec::<String>::from_value(value)?)
+    }
+}
+
+/// Conversion from a Rust type into a value, used to write channels.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+macro_rules! impl_into_value {
+    ($t:ty, $variant:ident) => {
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                Value::$variant(vec![self])
+            }
+        }
+
+        impl IntoValue for Vec<$t> {
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+
+        impl IntoValue for &[$t] {
+            fn into_value(self) -> Value {
+                Value::$variant(self.to_vec())
+            }
+        }
+    };
+}
+
+impl_into_value!(i16, Short);
+impl_into_value!(f32, Float);
+impl_into_value!(u16, Enum);
+impl_into_value!(u8, Char);
+impl_into_value!(i32, Long);
+impl_into_value!(f64, Double);
+impl_into_value!(String, String);
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(vec![self.to_string()])
+    }
+}
+
+impl IntoValue for &[&str] {
+    fn into_value(self) -> Value {
+        Value::String(self.iter().map(|s| s.to_string()).collect())
+    }
+}
+
+fn first<T>(values: Vec<T>) -> Result<T, Error> {
+    values.into_iter().next().ok_or_else(|| Error::ParseError("Value has no elements".into()))
+}
+
+/// DBR_STS_* structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sts {
+    pub alarm: Alarm,
+    pub value: Value,
+}
+
+/// DBR_TIME_* structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Time {
+    pub alarm: Alarm,
+    pub stamp: EpicsTime,
+    pub value: Value,
+}
+
+/// DBR_STSACK_STRING structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StsAck {
+    pub alarm: Alarm,
+    /// Whether transient alarms must be acknowledged.
+    pub ackt: u16,
+    /// Highest unacknowledged alarm severity.
+    pub acks: u16,
+    pub value: Vec<String>,
+}
+
+/// DBR_GR_* and DBR_CTRL_* structure.
+///
+/// Fields that the structure for a given field type does not carry (e.g. units for enums, precision for integers)
+/// are left at their defaults when decoding and ignored when encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graphic {
+    pub alarm: Alarm,
+    pub units: String,
+    pub precision: i16,
+    pub limits: Limits,
+    pub enum_strings: Vec<String>,
+    pub value: Value,
+}
+
+/// A decoded DBR payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dbr {
+    Plain(Value),
+    Sts(Sts),
+    Time(Time),
+    Gr(Graphic),
+    Ctrl(Graphic),
+    /// Acknowledge transient alarms flag to write.
+    PutAckt(u16),
+    /// Alarm severity to acknowledge.
+    PutAcks(u16),
+    StsAckString(StsAck),
+    /// Record type name.
+    ClassName(String),
+}
+impl Dbr {
+    /// Returns the data type this value is transferred as.
+    pub fn data_type(&self) -> DataType {
+        let (category, field_type) = match self {
+            Dbr::Plain(value) => (Category::Plain, value.field_type()),
+            Dbr::Sts(sts) => (Category::Sts, sts.value.field_type()),
+            Dbr::Time(time) => (Category::Time, time.value.field_type()),
+            Dbr::Gr(gr) => (Category::Gr, gr.value.field_type()),
+            Dbr::Ctrl(ctrl) => (Category::Ctrl, ctrl.value.field_type()),
+            Dbr::PutAckt(_) => (Category::PutAckt, FieldType::Enum),
+            Dbr::PutAcks(_) => (Category::PutAcks, FieldType::Enum),
+            Dbr::StsAckString(_) => (Category::StsAckString, FieldType::String),
+            Dbr::ClassName(_) => (Category::ClassName, FieldType::String),
+        };
+        DataType::from_parts(category, field_type)
+    }
+
+    /// Returns the value carried by the structure, or `None` for the alarm acknowledgment and class name types.
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Dbr::Plain(value) => Some(value),
+            Dbr::Sts(sts) => Some(&sts.value),
+            Dbr::Time(time) => Some(&time.value),
+            Dbr::Gr(gr) | Dbr::Ctrl(gr) => Some(&gr.value),
+            Dbr::PutAckt(_) | Dbr::PutAcks(_) | Dbr::StsAckString(_) | Dbr::ClassName(_) => None,
+        }
+    }
+
+    /// Consumes the structure and returns its value. Special types are converted into the equivalent plain value.
+    pub fn into_value(self) -> Value {
+        match self {
+            Dbr::Plain(value) => value,
+            Dbr::Sts(sts) => sts.value,
+            Dbr::Time(time) => time.value,
+            Dbr::Gr(gr) | Dbr::Ctrl(gr) => gr.value,
+            Dbr::PutAckt(ackt) => Value::Enum(vec![ackt]),
+            Dbr::PutAcks(acks) => Value::Enum(vec![acks]),
+            Dbr::StsAckString(stsack) => Value::String(stsack.value),
+            Dbr::ClassName(name) => Value::String(vec![name]),
+        }
+    }
+
+    /// Returns the alarm state, if the structure carries one.
+    pub fn alarm(&self) -> Option<Alarm> {
+        match self {
+            Dbr::Sts(sts) => Some(sts.alarm),
+            Dbr::Time(time) => Some(time.alarm),
+            Dbr::Gr(gr) | Dbr::Ctrl(gr) => Some(gr.alarm),
+            Dbr::StsAckString(stsack) => Some(stsack.alarm),
+            Dbr::Plain(_) | Dbr::PutAckt(_) | Dbr::PutAcks(_) | Dbr::ClassName(_) => None,
+        }
+    }
+
+    /// Returns the number of elements carried by the structure.
+    pub fn count(&self) -> usize {
+        match self {
+            Dbr::StsAckString(stsack) => stsack.value.len(),
+            Dbr::PutAckt(_) | Dbr::PutAcks(_) | Dbr::ClassName(_) => 1,
+            _ => self.value().map(Value::len).unwrap_or(0),
+        }
+    }
+
+    /// Returns the timestamp, if the structure carries one.
+    pub fn timestamp(&self) -> Option<EpicsTime> {
+        match self {
+            Dbr::Time(time) => Some(time.stamp),
+            _ => None,
+        }
+    }
+
+    /// Decodes a payload of `data_type` holding `count` elements.
+    pub fn from_bytes(data_type: DataType, count: usize, buf: &[u8]) -> Result<Self, Error> {
+        let category = data_type.category();
+        let field_type = data_type.field_type();
+        let mut reader = Reader::new(buf);
+
+        match category {
+            Category::Plain => return Ok(Dbr::Plain(Value::from_bytes(field_type, count, buf)?)),
+            Category::PutAckt => return Ok(Dbr::PutAckt(reader.u16()?)),
+            Category::PutAcks => return Ok(Dbr::PutAcks(reader.u16()?)),
+            Category::ClassName => return Ok(Dbr::ClassName(reader.string(buf.len().min(MAX_STRING_SIZE))?)),
+            _ => (),
+        }
+
+        let alarm = Alarm {
+            status: reader.u16()?,
+            severity: reader.u16()?,
+        };
+
+        let dbr = match category {
+            Category::Sts => {
+                reader.skip(value_offset(category, field_type) - 4)?;
+                Dbr::Sts(Sts { alarm, value: Value::from_bytes(field_type, count, reader.rest())? })
+            },
+            Category::Time => {
+                let stamp = EpicsTime { secs: reader.u32()?, nanos: reader.u32()? };
+                reader.skip(value_offset(category, field_type) - 12)?;
+                Dbr::Time(Time { alarm, stamp, value: Value::from_bytes(field_type, count, reader.rest())? })
+            },
+            Category::Gr | Category::Ctrl => {
+                let mut graphic = Graphic {
+                    alarm,
+                    units: String::new(),
+                    precision: 0,
+                    limits: Limits::default(),
+                    enum_strings: vec!(),
+                    value: Value::Char(vec!()),
+                };
+                match field_type {
+                    FieldType::String => (),
+                    FieldType::Enum => {
+                        let no_str = (reader.i16()?.max(0) as usize).min(MAX_ENUM_STATES);
+                        for i in 0..MAX_ENUM_STATES {
+                            let state = reader.string(MAX_ENUM_STRING_SIZE)?;
+                            if i < no_str {
+                                graphic.enum_strings.push(state);
+                            }
+                        }
+                    },
+                    _ => {
+                        if let FieldType::Float | FieldType::Double = field_type {
+                            graphic.precision = reader.i16()?;
+                            reader.skip(2)?;
+                        }
+                        graphic.units = reader.string(MAX_UNITS_SIZE)?;
+                        let limit_count = if category == Category::Ctrl { 8 } else { 6 };
+                        let mut limits = [0f64; 8];
+                        for limit in limits.iter_mut().take(limit_count) {
+                            *limit = reader.limit(field_type)?;
+                        }
+                        graphic.limits = Limits {
+                            upper_disp_limit: limits[0],
+                            lower_disp_limit: limits[1],
+                            upper_alarm_limit: limits[2],
+                            upper_warning_limit: limits[3],
+                            lower_warning_limit: limits[4],
+                            lower_alarm_limit: limits[5],
+                            upper_ctrl_limit: limits[6],
+                            lower_ctrl_limit: limits[7],
+                        };
+                        if field_type == FieldType::Char {
+                            reader.skip(1)?;
+                        }
+                    },
+                }
+                graphic.value = Value::from_bytes(field_type, count, reader.rest())?;
+                if category == Category::Gr { Dbr::Gr(graphic) } else { Dbr::Ctrl(graphic) }
+            },
+            _ => {
+                let ackt = reader.u16()?;
+                let acks = reader.u16()?;
+                let value = match Value::from_bytes(FieldType::String, count, reader.rest())? {
+                    Value::String(strings) => strings,
+                    _ => unreachable!(),
+                };
+                Dbr::StsAckString(StsAck { alarm, ackt, acks, value })
+            },
+        };
+
+        Ok(dbr)
+    }
+
+    /// Encodes the structure as big-endian bytes, including the padding required by its data type.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let data_type = self.data_type();
+        let category = data_type.category();
+        let field_type = data_type.field_type();
+
+        let mut buf = Vec::with_capacity(data_type.payload_size(self.count()));
+        if let Some(alarm) = self.alarm() {
+            buf.extend_from_slice(&alarm.status.to_be_bytes());
+            buf.extend_from_slice(&alarm.severity.to_be_bytes());
+        }
+
+        match self {
+            Dbr::Plain(value) => buf.extend(value.as_bytes()),
+            Dbr::PutAckt(value) | Dbr::PutAcks(value) => buf.extend_from_slice(&value.to_be_bytes()),
+            Dbr::ClassName(name) => write_string(&mut buf, name, MAX_STRING_SIZE),
+            Dbr::StsAckString(stsack) => {
+                buf.extend_from_slice(&stsack.ackt.to_be_bytes());
+                buf.extend_from_slice(&stsack.acks.to_be_bytes());
+                stsack.value.iter().for_each(|s| write_string(&mut buf, s, MAX_STRING_SIZE));
+            },
+            Dbr::Sts(_) => (),
+            Dbr::Time(time) => {
+                buf.extend_from_slice(&time.stamp.secs.to_be_bytes());
+                buf.extend_from_slice(&time.stamp.nanos.to_be_bytes());
+            },
+            Dbr::Gr(graphic) | Dbr::Ctrl(graphic) => match field_type {
+                FieldType::String => (),
+                FieldType::Enum => {
+                    let no_str = graphic.enum_strings.len().min(MAX_ENUM_STATES);
+                    buf.extend_from_slice(&(no_str as i16).to_be_bytes());
+                    for i in 0..MAX_ENUM_STATES {
+                        let state = graphic.enum_strings.get(i).map(String::as_str).unwrap_or("");
+                        write_string(&mut buf, state, MAX_ENUM_STRING_SIZE);
+                    }
+                },
+                _ => {
+                    if let FieldType::Float | FieldType::Double = field_type {
+                        buf.extend_from_slice(&graphic.precision.to_be_bytes());
+                        buf.extend_from_slice(&[0, 0]);
+                    }
+                    write_string(&mut buf, &graphic.units, MAX_UNITS_SIZE);
+                    let limits = &graphic.limits;
+                    let mut values = vec![
+                        limits.upper_disp_limit,
+                        limits.lower_disp_limit,
+                        limits.upper_alarm_limit,
+                        limits.upper_warning_limit,
+                        limits.lower_warning_limit,
+                        limits.lower_alarm_limit,
+                    ];
+                    if category == Category::Ctrl {
+                        values.push(limits.upper_ctrl_limit);
+                        values.push(limits.lower_ctrl_limit);
+                    }
+                    for limit in values {
+                        write_limit(&mut buf, field_type, limit);
+                    }
+                },
+            },
+        }
+
+        // Pad up to the value offset, then append the value
+        if let Dbr::Sts(_) | Dbr::Time(_) | Dbr::Gr(_) | Dbr::Ctrl(_) = self {
+            buf.resize(value_offset(category, field_type), 0);
+            buf.extend(self.value().expect("Structure carries a value").as_bytes());
+        }
+
+        buf
+    }
+}
+
+/// Offset of the value within a DBR structure, accounting for the RISC alignment padding defined in db_access.h.
+fn value_offset(category: Category, field_type: FieldType) -> usize {
+    match (category, field_type) {
+        (Category::Plain, _) | (Category::PutAckt, _) | (Category::PutAcks, _) | (Category::ClassName, _) => 0,
+        (Category::StsAckString, _) => 8,
+
+        (Category::Sts, FieldType::Char) => 5,
+        (Category::Sts, FieldType::Double) => 8,
+        (Category::Sts, _) => 4,
+
+        (Category::Time, FieldType::Short) | (Category::Time, FieldType::Enum) => 14,
+        (Category::Time, FieldType::Char) => 15,
+        (Category::Time, FieldType::Double) => 16,
+        (Category::Time, _) => 12,
+
+        (_, FieldType::String) => 4,
+        (_, FieldType::Enum) => 4 + 2 + MAX_ENUM_STATES * MAX_ENUM_STRING_SIZE,
+        (Category::Gr, FieldType::Short) => 4 + MAX_UNITS_SIZE + 6 * 2,
+        (Category::Gr, FieldType::Float) => 8 + MAX_UNITS_SIZE + 6 * 4,
+        (Category::Gr, FieldType::Char) => 4 + MAX_UNITS_SIZE + 6 + 1,
+        (Category::Gr, FieldType::Long) => 4 + MAX_UNITS_SIZE + 6 * 4,
+        (Category::Gr, FieldType::Double) => 8 + MAX_UNITS_SIZE + 6 * 8,
+        (Category::Ctrl, FieldType::Short) => 4 + MAX_UNITS_SIZE + 8 * 2,
+        (Category::Ctrl, FieldType::Float) => 8 + MAX_UNITS_SIZE + 8 * 4,
+        (Category::Ctrl, FieldType::Char) => 4 + MAX_UNITS_SIZE + 8 + 1,
+        (Category::Ctrl, FieldType::Long) => 4 + MAX_UNITS_SIZE + 8 * 4,
+        (Category::Ctrl, FieldType::Double) => 8 + MAX_UNITS_SIZE + 8 * 8,
+    }
+}
+
+/// Writes a null-terminated string into a fixed-size field, truncating if necessary.
+fn write_string(buf: &mut Vec<u8>, value: &str, size: usize) {
+    let bytes = value.as_bytes();
+    let len = bytes.len().min(size - 1);
+    buf.extend_from_slice(&bytes[..len]);
+    buf.resize(buf.len() + size - len, 0);
+}
+
+/// Writes a limit in the native representation of `field_type`.
+fn write_limit(buf: &mut Vec<u8>, field_type: FieldType, limit: f64) {
+    match field_type {
+        FieldType::Short => buf.extend_from_slice(&(limit as i16).to_be_bytes()),
+        FieldType::Float => buf.extend_from_slice(&(limit as f32).to_be_bytes()),
+        FieldType::Char => buf.push(limit as u8),
+        FieldType::Long => buf.extend_from_slice(&(limit as i32).to_be_bytes()),
+        FieldType::Double => buf.extend_from_slice(&limit.to_be_bytes()),
+        FieldType::String | FieldType::Enum => (),
+    }
+}
+
+/// Big-endian cursor over a payload.
+struct Reader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.position
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.position..]
+    }
+
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < size {
+            return Err(Error::BufferError(format!("Expected {} more bytes in DBR payload, got {}", size, self.remaining())))
+        }
+        let bytes = &self.buf[self.position..self.position + size];
+        self.position += size;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, size: usize) -> Result<(), Error> {
+        self.bytes(size).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a fixed-size, null-terminated string field.
+    fn string(&mut self, size: usize) -> Result<String, Error> {
+        let bytes = self.bytes(size)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    /// Reads a limit stored in the native representation of `field_type`.
+    fn limit(&mut self, field_type: FieldType) -> Result<f64, Error> {
+        Ok(match field_type {
+            FieldType::Short => self.i16()? as f64,
+            FieldType::Float => self.f32()? as f64,
+            FieldType::Char => self.bytes(1)?[0] as f64,
+            FieldType::Long => self.i32()? as f64,
+            FieldType::Double => self.f64()?,
+            FieldType::String | FieldType::Enum => 0.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_double_layout() {
+        let dbr = Dbr::Time(Time {
+            alarm: Alarm { status: 3, severity: 2 },
+            stamp: EpicsTime { secs: 1_000, nanos: 500 },
+            value: Value::Double(vec![1.5, -2.25]),
+        });
+        let buf = dbr.as_bytes();
+        assert_eq!(dbr.data_type(), DataType::DBR_TIME_DOUBLE);
+        assert_eq!(buf.len(), 16 + 2 * 8);
+        assert_eq!(&buf[16..24], &1.5f64.to_be_bytes());
+        assert_eq!(Dbr::from_bytes(DataType::DBR_TIME_DOUBLE, 2, &buf).unwrap(), dbr);
+    }
+
+    #[test]
+    fn ctrl_roundtrip() {
+        let limits = Limits {
+            upper_disp_limit: 10.0,
+            lower_disp_limit: -10.0,
+            upper_alarm_limit: 9.0,
+            upper_warning_limit: 8.0,
+            lower_warning_limit: -8.0,
+            lower_alarm_limit: -9.0,
+            upper_ctrl_limit: 5.0,
+            lower_ctrl_limit: -5.0,
+        };
+        let dbr = Dbr::Ctrl(Graphic {
+            alarm: Alarm::default(),
+            units: "mm".into(),
+            precision: 0,
+            limits,
+            enum_strings: vec!(),
+            value: Value::Short(vec![7]),
+        });
+        let buf = dbr.as_bytes();
+        assert_eq!(buf.len(), 28 + 2);
+        assert_eq!(Dbr::from_bytes(DataType::DBR_CTRL_SHORT, 1, &buf).unwrap(), dbr);
+
+        let dbr = Dbr::Gr(Graphic {
+            alarm: Alarm { status: 0, severity: 0 },
+            units: String::new(),
+            precision: 0,
+            limits: Limits::default(),
+            enum_strings: vec!["Off".into(), "On".into()],
+            value: Value::Enum(vec![1]),
+        });
+        let buf = dbr.as_bytes();
+        assert_eq!(buf.len(), 422 + 2);
+        assert_eq!(Dbr::from_bytes(DataType::DBR_GR_ENUM, 1, &buf).unwrap(), dbr);
+    }
+
+    #[test]
+    fn special_types() {
+        let dbr = Dbr::StsAckString(StsAck {
+            alarm: Alarm { status: 3, severity: 2 },
+            ackt: 1,
+            acks: 2,
+            value: vec!["12.5".into()],
+        });
+        let buf = dbr.as_bytes();
+        assert_eq!(buf.len(), 8 + MAX_STRING_SIZE);
+        assert_eq!(Dbr::from_bytes(DataType::DBR_STSACK_STRING, 1, &buf).unwrap(), dbr);
+
+        assert_eq!(Dbr::PutAcks(2).as_bytes(), vec![0, 2]);
+        assert_eq!(Dbr::PutAckt(0).data_type(), DataType::DBR_PUT_ACKT);
+
+        let class_name = Dbr::from_bytes(DataType::DBR_CLASS_NAME, 1, b"ai\0\0\0\0\0\0").unwrap();
+        assert_eq!(class_name, Dbr::ClassName("ai".into()));
+
+        let plain = Dbr::Plain(Value::Long(vec![1, -1]));
+        assert_eq!(plain.data_type(), DataType::DBR_LONG);
+        assert_eq!(Dbr::from_bytes(DataType::DBR_LONG, 2, &plain.as_bytes()).unwrap(), plain);
+    }
+
+    #[test]
+    fn data_type_parts() {
+        assert_eq!(DataType::DBR_CTRL_DOUBLE.category(), Category::Ctrl);
+        assert_eq!(DataType::DBR_CTRL_DOUBLE.field_type(), FieldType::Double);
+        assert_eq!(DataType::from_parts(Category::Time, FieldType::Enum), DataType::DBR_TIME_ENUM);
+        assert_eq!(DataType::DBR_DOUBLE.category(), Category::Plain);
+        assert_eq!(DataType::DBR_CLASS_NAME.field_type(), FieldType::String);
+    }
+
+    #[test]
+    fn value_conversion() {
+        assert_eq!(2.5f64.into_value().convert(FieldType::Long).unwrap(), Value::Long(vec![2]));
+        assert_eq!(" 12 ".into_value().convert(FieldType::Short).unwrap(), Value::Short(vec![12]));
+        assert_eq!((&[1u16, 2][..]).into_value().convert(FieldType::String).unwrap(), Value::String(vec!["1".into(), "2".into()]));
+        assert!("abc".into_value().convert(FieldType::Double).is_err());
+    }
+}
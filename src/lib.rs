@@ -1,9 +1,14 @@
 // Re-exports
 pub mod protocol;
+pub mod dbr;
 pub mod repeater;
 pub mod client;
+pub mod config;
 pub mod server;
 pub use client::Client;
+#[cfg(feature = "tokio")]
+pub use client::AsyncClient;
+pub use config::{ClientConfig, ServerConfig};
 pub use server::Server;
 
 // Imports
@@ -19,6 +24,7 @@ pub const CA_REPEATER_PORT: u16 = CA_PORT_BASE + MAJOR_PROTOCOL_VERSION *2 + 1;
 
 // Other Constants
 const CA_SERVER_BEACON_MAX_PERIOD: f64 = 15.0;
+#[allow(dead_code)]
 const CA_REPEATER_CLIENT_CHECK_PERIOD: f64 = 1.0;
 const LOCALHOST_U32: u32 = 0x7F000001;
 
@@ -27,7 +33,88 @@ const LOCALHOST_U32: u32 = 0x7F000001;
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::UdpSocket;
+    use protocol::{Message, Origin};
+    use std::net::SocketAddr;
+
+    /// Minimal stand-in for an IOC. Answers every search with its TCP port, accepts circuits one after
+    /// another and writes back whatever `respond` returns for each request received on them.
+    fn mock_server<F>(mut respond: F) -> SocketAddr
+    where F: FnMut(Message) -> Vec<Message> + Send + 'static {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, UdpSocket};
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let search_addr = udp.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((amt, src)) = udp.recv_from(&mut buf) {
+                for message in Message::all_from_bytes(&buf[..amt], Origin::Client).unwrap() {
+                    if let Message::Search { cid, .. } = message {
+                        let reply = Message::SearchResponse { port, server_ip: u32::MAX, cid, minor_version: 13 };
+                        udp.send_to(&reply.as_bytes(), src).unwrap();
+                    }
+                }
+            }
+        });
+
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = vec![0u8; 65536];
+                let mut data = vec!();
+                while let Ok(amt) = stream.read(&mut buf) {
+                    if amt == 0 { break; }
+                    data.extend_from_slice(&buf[..amt]);
+                    while let Ok((message, size)) = Message::from_bytes(&data, Origin::Client) {
+                        data.drain(..size);
+                        for reply in respond(message) {
+                            if stream.write_all(&reply.as_bytes()).is_err() { break; }
+                        }
+                    }
+                }
+            }
+        });
+
+        search_addr
+    }
+
+    /// Creates a client that only searches the given address.
+    fn test_client(search_addr: SocketAddr) -> client::Client {
+        client::Client::with_config(ClientConfig::default().addr_list(vec![search_addr]).auto_addr_list(false)).unwrap()
+    }
+
+    /// Starts a mock server whose monitors send four updates at once, returning a client configuration
+    /// pausing events at three unread updates and resuming at one, and a receiver for the EVENTS_OFF
+    /// and EVENTS_ON messages the server gets.
+    fn flow_control_server() -> (ClientConfig, std::sync::mpsc::Receiver<Message>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 5, data_count: 1, cid, sid: 3 },
+            ],
+            Message::EventAdd { data_type, subscription_id, .. } => (0..4).map(|x| Message::EventAddResponse {
+                data_type,
+                data_count: 1,
+                status: protocol::eca::ECA_NORMAL,
+                subscription_id,
+                payload: dbr::Value::Long(vec![x]).as_bytes(),
+            }).collect(),
+            Message::EventsOff | Message::EventsOn => {
+                tx.send(message).unwrap();
+                vec!()
+            },
+            _ => vec!(),
+        });
+
+        let config = ClientConfig::default()
+            .addr_list(vec![search_addr])
+            .auto_addr_list(false)
+            .flow_control_high(3)
+            .flow_control_low(1);
+        (config, rx)
+    }
 
     #[test]
     fn client_registration() {
@@ -39,5 +126,741 @@ mod tests {
 
         assert!(client.is_registered());
     }
-}
 
+    #[test]
+    fn client_search() {
+        use std::net::UdpSocket;
+
+        // Fake server answering the first search request it receives
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (amt, src) = server.recv_from(&mut buf).unwrap();
+            let messages = Message::all_from_bytes(&buf[..amt], Origin::Client).unwrap();
+            if let Some(Message::Search { cid, name, .. }) = messages.last() {
+                assert_eq!(name, "TEST:PV");
+                let reply = Message::SearchResponse { port: 5999, server_ip: u32::MAX, cid: *cid, minor_version: 13 };
+                server.send_to(&reply.as_bytes(), src).unwrap();
+            }
+        });
+
+        let client = test_client(server_addr);
+
+        let result = client.search_timeout("TEST:PV", std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(result.address, "127.0.0.1:5999".parse().unwrap());
+        assert_eq!(result.minor_version, 13);
+    }
+
+    #[test]
+    fn client_circuit() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Fake server checking the handshake and answering with its own version
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![0u8; 4096];
+            let mut data = vec!();
+            let mut received = vec!();
+            while received.len() < 3 {
+                let amt = stream.read(&mut buf).unwrap();
+                data.extend_from_slice(&buf[..amt]);
+                received = Message::all_from_bytes(&data, Origin::Client).unwrap_or_default();
+            }
+            assert_eq!(received[0], Message::Version { priority: 10, minor_version: MINOR_PROTOCOL_VERSION });
+            assert!(matches!(received[1], Message::ClientName { .. }));
+            assert!(matches!(received[2], Message::HostName { .. }));
+            stream.write_all(&Message::Version { priority: 10, minor_version: 13 }.as_bytes()).unwrap();
+
+            // The client closes the circuit once its last channel is detached
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        });
+
+        let client = client::Client::with_config(ClientConfig::default().auto_addr_list(false)).unwrap();
+        let circuit = client.circuit(server_addr, 10).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&circuit, &client.circuit(server_addr, 10).unwrap()));
+
+        circuit.attach_channel(1, std::sync::Arc::new(|_| ()));
+        circuit.attach_channel(2, std::sync::Arc::new(|_| ()));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(circuit.server_minor_version(), Some(13));
+
+        circuit.detach_channel(1);
+        assert!(!circuit.is_closed());
+        circuit.detach_channel(2);
+        assert!(circuit.is_closed());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_shutdown() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client::Client::with_config(ClientConfig::default().auto_addr_list(false)).unwrap();
+        let circuit = client.circuit(listener.local_addr().unwrap(), 0).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+
+        // Dropping the client stops its event loop, which closes the circuit right away
+        drop(client);
+        let mut data = vec!();
+        stream.read_to_end(&mut data).unwrap();
+        assert!(circuit.is_closed());
+    }
+
+    #[test]
+    fn channel_lifecycle() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, name, .. } => {
+                assert_eq!(name, "TEST:CHANNEL");
+                vec![
+                    Message::AccessRights { cid, access_rights: 3 },
+                    Message::CreateChanResponse { data_type: 6, data_count: 1, cid, sid: 100 },
+                ]
+            },
+            Message::ClearChannel { sid, cid } => {
+                tx.send((sid, cid)).unwrap();
+                vec![Message::ClearChannel { sid, cid }]
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:CHANNEL");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(channel.state(), client::ConnectionState::Connected);
+        assert_eq!(channel.native_type(), Some(protocol::DataType::DBR_DOUBLE));
+        assert_eq!(channel.element_count(), Some(1));
+        assert_eq!(channel.sid(), Some(100));
+
+        let cid = channel.cid();
+        drop(channel);
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap(), (100, cid));
+    }
+
+    #[test]
+    fn channel_create_failure() {
+        let search_addr = mock_server(|message| match message {
+            Message::CreateChan { cid, .. } => vec![Message::CreateChFail { cid }],
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:MISSING");
+        assert!(matches!(channel.wait_connected(std::time::Duration::from_secs(2)), Err(client::Error::ChannelError(_))));
+        assert_eq!(channel.state(), client::ConnectionState::Failed);
+    }
+
+    #[test]
+    fn channel_get() {
+        let search_addr = mock_server(|message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 6, data_count: 3, cid, sid: 7 },
+            ],
+            Message::ReadNotify { data_type: 6, data_count, ioid, .. } => {
+                let value = dbr::Value::Double(vec![1.5, 2.5, 3.5][..data_count as usize].to_vec());
+                vec![Message::ReadNotifyResponse { data_type: 6, data_count, status: protocol::eca::ECA_NORMAL, ioid, payload: value.as_bytes() }]
+            },
+            Message::ReadNotify { data_type, data_count, ioid, .. } => vec![
+                Message::ReadNotifyResponse { data_type, data_count, status: protocol::eca::ECA_NORDACCESS, ioid, payload: vec!() },
+            ],
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:ARRAY");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+
+        assert_eq!(channel.get::<f64>().unwrap(), 1.5);
+        assert_eq!(channel.get::<Vec<f64>>().unwrap(), vec![1.5, 2.5, 3.5]);
+        assert!(matches!(channel.get::<i32>(), Err(client::Error::EcaError(protocol::eca::ECA_NORDACCESS, _))));
+    }
+
+    #[test]
+    fn channel_put() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 1, data_count: 4, cid, sid: 9 },
+            ],
+            Message::Write { data_type, data_count, payload, .. } => {
+                tx.send((data_type, data_count, payload)).unwrap();
+                vec!()
+            },
+            Message::WriteNotify { data_type, data_count, ioid, .. } => {
+                let status = if data_count > 1 { protocol::eca::ECA_NOWTACCESS } else { protocol::eca::ECA_NORMAL };
+                vec![Message::WriteNotifyResponse { data_type, data_count, status, ioid }]
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:SHORTS");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+
+        channel.put(&[1.0, 2.0][..]).unwrap();
+        let (data_type, data_count, payload) = rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!((data_type, data_count), (1, 2));
+        assert_eq!(&payload[..4], &[0, 1, 0, 2]);
+
+        channel.put_callback("7").unwrap();
+        assert!(matches!(channel.put_callback(vec![1i32, 2]), Err(client::Error::EcaError(protocol::eca::ECA_NOWTACCESS, _))));
+        assert!(channel.put_callback("seven").is_err());
+    }
+
+    #[test]
+    fn channel_put_enum_string() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 3, data_count: 1, cid, sid: 4 },
+            ],
+            Message::WriteNotify { data_type, data_count, ioid, payload, .. } => {
+                // The server maps state names through its enum strings
+                let field_type = if data_type == 0 { dbr::FieldType::String } else { dbr::FieldType::Enum };
+                let value = dbr::Value::from_bytes(field_type, data_count as usize, &payload).unwrap();
+                let status = match &value {
+                    dbr::Value::String(names) if names[0] != "On" => protocol::eca::ECA_NOCONVERT,
+                    _ => protocol::eca::ECA_NORMAL,
+                };
+                tx.send(value).unwrap();
+                vec![Message::WriteNotifyResponse { data_type, data_count, status, ioid }]
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:ENUM");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+        let timeout = std::time::Duration::from_secs(2);
+
+        // State names go out as DBR_STRING, state numbers as DBR_ENUM
+        channel.put_callback("On").unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), dbr::Value::String(vec!["On".into()]));
+        channel.put_callback(1u16).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), dbr::Value::Enum(vec![1]));
+        assert!(matches!(channel.put_callback("Standby"), Err(client::Error::EcaError(protocol::eca::ECA_NOCONVERT, _))));
+    }
+
+    #[test]
+    fn channel_monitor() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 5, data_count: 1, cid, sid: 3 },
+            ],
+            Message::EventAdd { data_type, subscription_id, mask, .. } => {
+                assert_eq!(mask, protocol::DBE_VALUE | protocol::DBE_ALARM);
+                (1..=2).map(|x| Message::EventAddResponse {
+                    data_type,
+                    data_count: 1,
+                    status: protocol::eca::ECA_NORMAL,
+                    subscription_id,
+                    payload: dbr::Value::Long(vec![x]).as_bytes(),
+                }).collect()
+            },
+            Message::EventCancel { subscription_id, .. } => {
+                tx.send(subscription_id).unwrap();
+                vec!()
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:COUNTER");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+
+        let monitor = channel.monitor(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE | protocol::DBE_ALARM).unwrap();
+        let id = monitor.id();
+        let values: Vec<dbr::Value> = monitor.take(2).map(|update| update.unwrap().into_value()).collect();
+        assert_eq!(values, vec![dbr::Value::Long(vec![1]), dbr::Value::Long(vec![2])]);
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap(), id);
+    }
+
+    #[test]
+    fn monitor_flow_control() {
+        let (config, rx) = flow_control_server();
+        let client = client::Client::with_config(config).unwrap();
+        let channel = client.create_channel("TEST:FAST");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+        let timeout = std::time::Duration::from_secs(2);
+
+        let monitor = channel.monitor(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOff);
+        for _ in 0..3 {
+            monitor.recv_timeout(timeout).unwrap().unwrap();
+        }
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOn);
+
+        // The last update may still be on its way
+        let circuit = client.circuit(channel.server_address().unwrap(), 0).unwrap();
+        let start = std::time::Instant::now();
+        while circuit.backlog() < 1 && start.elapsed() < timeout {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(circuit.backlog(), 1);
+        client.pause_events().unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOff);
+        monitor.recv_timeout(timeout).unwrap().unwrap();
+        assert!(circuit.events_paused());
+        client.resume_events().unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOn);
+    }
+
+    #[test]
+    fn dropped_monitor_flow_control() {
+        let (config, rx) = flow_control_server();
+        let client = client::Client::with_config(config).unwrap();
+        let channel = client.create_channel("TEST:FAST");
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+        let timeout = std::time::Duration::from_secs(2);
+        let circuit = client.circuit(channel.server_address().unwrap(), 0).unwrap();
+
+        // Unread updates of a dropped monitor no longer hold the circuit's events off
+        let monitor = channel.monitor(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOff);
+        let start = std::time::Instant::now();
+        while circuit.backlog() < 4 && start.elapsed() < timeout {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(circuit.backlog(), 4);
+        drop(monitor);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOn);
+        assert_eq!(circuit.backlog(), 0);
+        assert!(!circuit.events_paused());
+    }
+
+    #[test]
+    fn unresponsive_circuit() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let search_addr = mock_server(move |message| match message {
+            // Never answers CA_PROTO_ECHO
+            Message::CreateChan { cid, .. } => {
+                tx.send(message.command()).unwrap();
+                vec![Message::CreateChanResponse { data_type: 6, data_count: 1, cid, sid: 1 }]
+            },
+            Message::Echo => {
+                tx.send(message.command()).unwrap();
+                vec!()
+            },
+            _ => vec!(),
+        });
+
+        let config = ClientConfig::default()
+            .addr_list(vec![search_addr])
+            .auto_addr_list(false)
+            .conn_tmo(std::time::Duration::from_millis(300));
+        let client = client::Client::with_config(config).unwrap();
+        let channel = client.create_channel("TEST:HUNG");
+        let timeout = std::time::Duration::from_secs(5);
+
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), protocol::Command::CA_PROTO_CREATE_CHAN);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), protocol::Command::CA_PROTO_ECHO);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), protocol::Command::CA_PROTO_CREATE_CHAN);
+        channel.wait_connected(timeout).unwrap();
+    }
+
+    #[test]
+    fn subscription_restored() {
+        let mut connections = 0;
+        let mut channel_cid = 0;
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => {
+                connections += 1;
+                channel_cid = cid;
+                vec![Message::CreateChanResponse { data_type: 5, data_count: 1, cid, sid: connections }]
+            },
+            Message::EventAdd { data_type, sid, subscription_id, .. } => {
+                let mut replies = vec![Message::EventAddResponse {
+                    data_type,
+                    data_count: 1,
+                    status: protocol::eca::ECA_NORMAL,
+                    subscription_id,
+                    payload: dbr::Value::Long(vec![sid as i32]).as_bytes(),
+                }];
+                // The first server instance drops the channel after one update
+                if sid == 1 {
+                    replies.push(Message::ServerDisconn { cid: channel_cid });
+                }
+                replies
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:RESTART");
+        let monitor = channel.monitor(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+
+        assert_eq!(monitor.recv_timeout(timeout).unwrap().unwrap().into_value(), dbr::Value::Long(vec![1]));
+        assert!(matches!(monitor.recv_timeout(timeout), Some(Err(client::Error::ChannelError(_)))));
+        assert_eq!(monitor.recv_timeout(timeout).unwrap().unwrap().into_value(), dbr::Value::Long(vec![2]));
+        assert!(channel.is_connected());
+    }
+
+    #[test]
+    fn channel_access_rights() {
+        let search_addr = mock_server(|message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::AccessRights { cid, access_rights: protocol::AccessRights::READ },
+                Message::CreateChanResponse { data_type: 6, data_count: 1, cid, sid: 4 },
+            ],
+            Message::WriteNotify { .. } | Message::Write { .. } => panic!("Write sent without write access"),
+            Message::ReadNotify { data_type, data_count, ioid, .. } => vec![Message::ReadNotifyResponse {
+                data_type,
+                data_count,
+                status: protocol::eca::ECA_NORMAL,
+                ioid,
+                payload: dbr::Value::Double(vec![4.0]).as_bytes(),
+            }],
+            _ => vec!(),
+        });
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:READONLY");
+        channel.on_access_rights_change(move |rights| tx.lock().unwrap().send(rights).unwrap());
+        channel.wait_connected(std::time::Duration::from_secs(2)).unwrap();
+
+        let read_only = protocol::AccessRights { read: true, write: false };
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap(), read_only);
+        assert_eq!(channel.access_rights(), Some(read_only));
+        assert!(matches!(channel.put(1.0), Err(client::Error::AccessError(_))));
+        assert!(matches!(channel.put_callback(1.0), Err(client::Error::AccessError(_))));
+        assert_eq!(channel.get::<f64>().unwrap(), 4.0);
+    }
+
+    #[test]
+    fn channel_events() {
+        let mut connections = 0;
+        let search_addr = mock_server(move |message| match message {
+            Message::CreateChan { cid, .. } => {
+                connections += 1;
+                let mut replies = vec![
+                    Message::AccessRights { cid, access_rights: 3 },
+                    Message::CreateChanResponse { data_type: 6, data_count: 2, cid, sid: connections },
+                ];
+                if connections == 1 {
+                    replies.push(Message::ServerDisconn { cid });
+                }
+                replies
+            },
+            _ => vec!(),
+        });
+
+        let client = test_client(search_addr);
+        let channel = client.create_channel("TEST:EVENTS");
+        let events = channel.events();
+        let timeout = std::time::Duration::from_secs(5);
+        let next = || events.recv_timeout(timeout).unwrap();
+
+        let read_write = protocol::AccessRights { read: true, write: true };
+        assert_eq!(next(), client::ChannelEvent::AccessRightsChanged(read_write));
+        let connected = next();
+        assert!(matches!(connected, client::ChannelEvent::Connected {
+            native_type: protocol::DataType::DBR_DOUBLE,
+            element_count: 2,
+            ..
+        }));
+        assert_eq!(next(), client::ChannelEvent::Disconnected);
+        assert_eq!(next(), client::ChannelEvent::AccessRightsChanged(protocol::AccessRights::default()));
+        assert_eq!(next(), client::ChannelEvent::AccessRightsChanged(read_write));
+        assert_eq!(next(), connected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_client() {
+        use futures_core::Stream;
+
+        let search_addr = mock_server(|message| match message {
+            Message::CreateChan { cid, .. } => vec![
+                Message::CreateChanResponse { data_type: 5, data_count: 1, cid, sid: 2 },
+            ],
+            Message::ReadNotify { data_type, data_count, ioid, .. } => vec![Message::ReadNotifyResponse {
+                data_type,
+                data_count,
+                status: protocol::eca::ECA_NORMAL,
+                ioid,
+                payload: dbr::Value::Long(vec![42]).as_bytes(),
+            }],
+            // Writes of zero never complete
+            Message::WriteNotify { payload, .. } if payload[..4] == [0; 4] => vec!(),
+            Message::WriteNotify { data_type, data_count, ioid, .. } => vec![
+                Message::WriteNotifyResponse { data_type, data_count, status: protocol::eca::ECA_NORMAL, ioid },
+            ],
+            Message::EventAdd { data_type, subscription_id, .. } => vec![Message::EventAddResponse {
+                data_type,
+                data_count: 1,
+                status: protocol::eca::ECA_NORMAL,
+                subscription_id,
+                payload: dbr::Value::Long(vec![7]).as_bytes(),
+            }],
+            _ => vec!(),
+        });
+
+        let config = ClientConfig::default().addr_list(vec![search_addr]).auto_addr_list(false);
+        let client = client::AsyncClient::with_config(config).unwrap();
+        assert!(client.search("TEST:ASYNC").await.is_ok());
+
+        let channel = client.connect("TEST:ASYNC").await.unwrap();
+        assert_eq!(channel.get::<i32>().await.unwrap(), 42);
+        channel.put_callback(43).await.unwrap();
+        let timeout = std::time::Duration::from_millis(100);
+        assert_eq!(channel.get_timeout::<i32>(timeout).await.unwrap(), 42);
+        assert!(matches!(channel.put_callback_timeout(0, timeout).await, Err(client::Error::TimeoutError(_))));
+
+        let mut monitor = channel.subscribe(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE).unwrap();
+        let update = std::future::poll_fn(|cx| std::pin::Pin::new(&mut monitor).poll_next(cx)).await;
+        assert_eq!(update.unwrap().unwrap().into_value(), dbr::Value::Long(vec![7]));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_flow_control() {
+        use futures_core::Stream;
+
+        let (config, rx) = flow_control_server();
+        let client = client::AsyncClient::with_config(config).unwrap();
+        let channel = client.connect("TEST:FAST").await.unwrap();
+        let timeout = std::time::Duration::from_secs(2);
+
+        // A stream that is not polled holds events off until it catches up
+        let mut monitor = channel.subscribe(protocol::DataType::DBR_LONG, 1, protocol::DBE_VALUE).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOff);
+        for x in 0..3 {
+            let update = std::future::poll_fn(|cx| std::pin::Pin::new(&mut monitor).poll_next(cx)).await;
+            assert_eq!(update.unwrap().unwrap().into_value(), dbr::Value::Long(vec![x]));
+        }
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Message::EventsOn);
+    }
+
+    /// Variable holding a fixed-length value in a minor HIGH alarm, completing writes on another thread.
+    struct TestPv {
+        value: std::sync::Mutex<dbr::Value>,
+        notifier: std::sync::Mutex<Option<server::ChangeNotifier>>,
+    }
+    impl TestPv {
+        fn new(value: dbr::Value) -> std::sync::Arc<Self> {
+            std::sync::Arc::new(Self { value: std::sync::Mutex::new(value), notifier: std::sync::Mutex::new(None) })
+        }
+    }
+    impl server::ProcessVariable for TestPv {
+        fn native_type(&self) -> dbr::FieldType {
+            self.value.lock().unwrap().field_type()
+        }
+        fn element_count(&self) -> usize {
+            self.value.lock().unwrap().len()
+        }
+        fn read(&self) -> Result<dbr::Time, server::Error> {
+            Ok(dbr::Time {
+                alarm: dbr::Alarm { status: dbr::alarm::status::HIGH_ALARM, severity: dbr::alarm::severity::MINOR_ALARM },
+                stamp: dbr::EpicsTime::now(),
+                value: self.value.lock().unwrap().clone(),
+            })
+        }
+        fn write(&self, mut value: dbr::Value, completion: server::WriteCompletion) {
+            {
+                let mut stored = self.value.lock().unwrap();
+                value.resize(stored.len());
+                *stored = value;
+            }
+            if let Some(notifier) = &*self.notifier.lock().unwrap() {
+                notifier.post(protocol::DBE_VALUE | protocol::DBE_LOG);
+            }
+            std::thread::spawn(move || completion.complete(Ok(())));
+        }
+        fn attach(&self, notifier: server::ChangeNotifier) {
+            *self.notifier.lock().unwrap() = Some(notifier);
+        }
+    }
+
+    fn test_server() -> (Server, client::Client) {
+        let server = Server::with_config(ServerConfig::default()
+            .interface([127, 0, 0, 1].into())
+            .server_port(0)
+            .auto_beacon_addr_list(false)).unwrap();
+        let client = test_client(server.search_address());
+        (server, client)
+    }
+
+    #[test]
+    fn server_requests() {
+        use std::time::Duration;
+
+        let (server, client) = test_server();
+        server.add_pv("TEST:SERVER", TestPv::new(dbr::Value::Double(vec![1.5, 2.5])));
+
+        let channel = client.create_channel("TEST:SERVER");
+        channel.wait_connected(Duration::from_secs(2)).unwrap();
+        assert_eq!(channel.native_type(), Some(protocol::DataType::DBR_DOUBLE));
+        assert_eq!(channel.element_count(), Some(2));
+        assert_eq!(channel.get::<Vec<f64>>().unwrap(), vec![1.5, 2.5]);
+        assert_eq!(channel.get::<String>().unwrap(), "1.5");
+
+        let monitor = channel.monitor(protocol::DataType::DBR_TIME_DOUBLE, 1, protocol::DBE_VALUE).unwrap();
+        let first = monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(first.alarm(), Some(dbr::Alarm { status: dbr::alarm::status::HIGH_ALARM, severity: dbr::alarm::severity::MINOR_ALARM }));
+        assert_eq!(first.into_value(), dbr::Value::Double(vec![1.5]));
+
+        channel.put_callback(vec![3.0, 4.0]).unwrap();
+        channel.put("5").unwrap();
+        assert_eq!(monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap().into_value(), dbr::Value::Double(vec![3.0]));
+        assert_eq!(monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap().into_value(), dbr::Value::Double(vec![5.0]));
+        assert_eq!(channel.get::<Vec<f64>>().unwrap(), vec![5.0, 0.0]);
+        assert!(matches!(channel.put_callback(vec![1.0, 2.0, 3.0]), Err(client::Error::EcaError(protocol::eca::ECA_BADCOUNT, _))));
+
+        // Unknown names are never answered; removed ones disconnect their channels
+        let missing = client.create_channel("TEST:MISSING");
+        assert!(missing.wait_connected(Duration::from_millis(200)).is_err());
+        let events = channel.events();
+        assert!(server.remove_pv("TEST:SERVER"));
+        assert_eq!(events.recv_timeout(Duration::from_secs(1)).unwrap(), client::ChannelEvent::Disconnected);
+    }
+
+    #[test]
+    fn server_database() {
+        use std::time::Duration;
+        use server::{PvDatabase, SimplePv};
+
+        let (server, client) = test_server();
+        let mut database = PvDatabase::new();
+        let temperature = database.add("TEST:TEMP", SimplePv::new(20.0)
+            .units("degC")
+            .precision(2)
+            .display_limits(-50.0, 150.0)
+            .control_limits(0.0, 100.0)
+            .alarm_limits(-10.0, 0.0, 80.0, 90.0));
+        database.add("TEST:MODE", SimplePv::new(0u16).enum_strings(&["Off", "On"]));
+        server.add_database(&database);
+
+        let channel = client.create_channel("TEST:TEMP");
+        channel.wait_connected(Duration::from_secs(2)).unwrap();
+        match channel.get_dbr(protocol::DataType::DBR_CTRL_DOUBLE, 1).unwrap() {
+            dbr::Dbr::Ctrl(ctrl) => {
+                assert_eq!(ctrl.units, "degC");
+                assert_eq!(ctrl.precision, 2);
+                assert_eq!(ctrl.limits.upper_disp_limit, 150.0);
+                assert_eq!(ctrl.limits.upper_ctrl_limit, 100.0);
+                assert_eq!(ctrl.limits.upper_warning_limit, 80.0);
+                assert_eq!(ctrl.limits.lower_alarm_limit, -10.0);
+                assert_eq!(ctrl.value, dbr::Value::Double(vec![20.0]));
+            },
+            dbr => panic!("Unexpected {:?}", dbr),
+        }
+
+        // Writes are clamped to the control limits and raise alarms past the alarm limits
+        let monitor = channel.monitor(protocol::DataType::DBR_STS_DOUBLE, 1, protocol::DBE_ALARM).unwrap();
+        assert_eq!(monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap().alarm(), Some(dbr::Alarm::default()));
+        channel.put_callback(120.0).unwrap();
+        let update = monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(update.alarm(), Some(dbr::Alarm { status: dbr::alarm::status::HIHI_ALARM, severity: dbr::alarm::severity::MAJOR_ALARM }));
+        assert_eq!(update.into_value(), dbr::Value::Double(vec![100.0]));
+        temperature.set(85.0).unwrap();
+        let update = monitor.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(update.alarm(), Some(dbr::Alarm { status: dbr::alarm::status::HIGH_ALARM, severity: dbr::alarm::severity::MINOR_ALARM }));
+
+        // Enum states are read and written by name
+        let mode = client.create_channel("TEST:MODE");
+        mode.wait_connected(Duration::from_secs(2)).unwrap();
+        assert_eq!(mode.get::<String>().unwrap(), "Off");
+        mode.put_callback("On").unwrap();
+        assert_eq!(mode.get::<u16>().unwrap(), 1);
+        assert!(matches!(mode.put_callback("Standby"), Err(client::Error::EcaError(protocol::eca::ECA_NOCONVERT, _))));
+        mode.put_callback(0u16).unwrap();
+        assert_eq!(mode.get::<String>().unwrap(), "Off");
+        match mode.get_dbr(protocol::DataType::DBR_GR_ENUM, 1).unwrap() {
+            dbr::Dbr::Gr(gr) => assert_eq!(gr.enum_strings, vec!["Off", "On"]),
+            dbr => panic!("Unexpected {:?}", dbr),
+        }
+    }
+
+    #[test]
+    fn server_beacons() {
+        use std::time::Duration;
+
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let server = Server::with_config(ServerConfig::default()
+            .interface([127, 0, 0, 1].into())
+            .server_port(0)
+            .beacon_addr_list(vec![receiver.local_addr().unwrap()])
+            .auto_beacon_addr_list(false)).unwrap();
+
+        let mut buf = [0u8; 1024];
+        for expected_id in 0..4 {
+            let (amt, _) = receiver.recv_from(&mut buf).unwrap();
+            match protocol::Message::from_bytes(&buf[..amt], protocol::Origin::Server).unwrap().0 {
+                protocol::Message::RsrvIsUp { beacon_id, port, address, .. } => {
+                    assert_eq!(beacon_id, expected_id);
+                    assert_eq!(port, server.tcp_address().port());
+                    assert_eq!(address, LOCALHOST_U32);
+                },
+                message => panic!("Unexpected {:?}", message),
+            }
+        }
+
+        // Beacons stop with the server, bar one that may already be on its way
+        drop(server);
+        receiver.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let mut late = 0;
+        while receiver.recv_from(&mut buf).is_ok() {
+            late += 1;
+        }
+        assert!(late <= 1);
+    }
+
+    #[test]
+    fn server_monitor_queue() {
+        use std::time::Duration;
+        use server::SimplePv;
+
+        let server = Server::with_config(ServerConfig::default()
+            .interface([127, 0, 0, 1].into())
+            .server_port(0)
+            .auto_beacon_addr_list(false)
+            .event_queue_size(2)).unwrap();
+        let client = test_client(server.search_address());
+        let pv = std::sync::Arc::new(SimplePv::new(0.0).value_deadband(1.0).archive_deadband(5.0));
+        server.add_pv("TEST:QUEUE", pv.clone());
+
+        let channel = client.create_channel("TEST:QUEUE");
+        channel.wait_connected(Duration::from_secs(2)).unwrap();
+        let value = channel.monitor(protocol::DataType::DBR_DOUBLE, 1, protocol::DBE_VALUE).unwrap();
+        let log = channel.monitor(protocol::DataType::DBR_TIME_DOUBLE, 1, protocol::DBE_LOG).unwrap();
+        let next = |monitor: &client::Monitor| monitor.recv_timeout(Duration::from_millis(300)).map(|dbr| dbr.unwrap().into_value());
+        assert_eq!(next(&value), Some(dbr::Value::Double(vec![0.0])));
+        assert_eq!(next(&log), Some(dbr::Value::Double(vec![0.0])));
+
+        // Each subscription only sees the changes past the deadband of its event mask
+        for x in [0.5, 1.5, 2.0, 6.0] {
+            pv.set(x).unwrap();
+        }
+        assert_eq!(next(&value), Some(dbr::Value::Double(vec![1.5])));
+        assert_eq!(next(&value), Some(dbr::Value::Double(vec![6.0])));
+        assert_eq!(next(&value), None);
+        assert_eq!(next(&log), Some(dbr::Value::Double(vec![6.0])));
+        assert_eq!(next(&log), None);
+
+        // While events are off, updates queue up and coalesce into the latest value
+        client.pause_events().unwrap();
+        channel.get::<f64>().unwrap();
+        for x in [10.0, 20.0, 30.0, 40.0] {
+            pv.set(x).unwrap();
+        }
+        assert_eq!(next(&value), None);
+        client.resume_events().unwrap();
+        assert_eq!(next(&value), Some(dbr::Value::Double(vec![10.0])));
+        assert_eq!(next(&value), Some(dbr::Value::Double(vec![40.0])));
+        assert_eq!(next(&value), None);
+        assert_eq!(next(&log), Some(dbr::Value::Double(vec![20.0])));
+        assert_eq!(next(&log), Some(dbr::Value::Double(vec![40.0])));
+    }
+}
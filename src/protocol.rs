@@ -1,15 +1,21 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+
+use log::{error, warn};
 
 pub const HEADER_SIZE: usize = 16;
 pub const EXTENDED_HEADER_SIZE: usize = 24;
 
-#[derive(Debug)]
+/// Payload size value marking a message that uses the extended header.
+const EXTENDED_MARKER: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     BufferError(String),
     ParseError(String),
 }
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     /// UDP/TCP (0x00) Exchanges client and server protocol versions and desired circuit priority. MUST be the first message sent, by both client and server, when a new TCP (Virtual Circuit) connection is established. It is also sent as the first message in UDP search messages.
     CA_PROTO_VERSION,
@@ -92,10 +98,10 @@ pub enum Command {
     /// TCP (0x1B) Notifies the client that server has disconnected the channel. This may be since the channel has been destroyed on server.
     CA_PROTO_SERVER_DISCONN,
 }
-impl Into<u16> for Command {
+impl From<Command> for u16 {
     /// Returns ID value of the command variant as u16
-    fn into(self) -> u16 {
-        match self {
+    fn from(command: Command) -> u16 {
+        match command {
             Command::CA_PROTO_VERSION => 0x00,
             Command::CA_PROTO_SEARCH => 0x06,
             Command::CA_PROTO_NOT_FOUND => 0x0E,
@@ -166,7 +172,29 @@ impl std::convert::TryFrom<u16> for Command {
 
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
+    /// (0x00) Null-terminated string of at most 40 characters.
+    DBR_STRING,
+
+    /// (0x01) INT16 value. May be referred to as DBR_INT.
+    DBR_SHORT,
+
+    /// (0x02) FLOAT value.
+    DBR_FLOAT,
+
+    /// (0x03) UINT16 index into the enum states.
+    DBR_ENUM,
+
+    /// (0x04) UINT8 value.
+    DBR_CHAR,
+
+    /// (0x05) INT32 value.
+    DBR_LONG,
+
+    /// (0x06) DOUBLE value.
+    DBR_DOUBLE,
+
     /// (0x07) DBR_STS structure for string type.
     DBR_STS_STRING,
 
@@ -257,7 +285,115 @@ pub enum DataType {
     /// (0x22) DBR_CTRL structure for DOUBLE type.
     DBR_CTRL_DOUBLE,
 
+    /// (0x23) Write-only UINT16 setting whether transient alarms must be acknowledged.
+    DBR_PUT_ACKT,
 
+    /// (0x24) Write-only UINT16 acknowledging alarms up to the given severity.
+    DBR_PUT_ACKS,
+
+    /// (0x25) DBR_STS structure for string type extended with the acknowledge transient flag and acknowledge severity.
+    DBR_STSACK_STRING,
+
+    /// (0x26) Read-only string holding the record type of the channel.
+    DBR_CLASS_NAME,
+}
+impl From<DataType> for u16 {
+    /// Returns ID value of the data type variant as u16
+    fn from(data_type: DataType) -> u16 {
+        match data_type {
+            DataType::DBR_STRING => 0x00,
+            DataType::DBR_SHORT => 0x01,
+            DataType::DBR_FLOAT => 0x02,
+            DataType::DBR_ENUM => 0x03,
+            DataType::DBR_CHAR => 0x04,
+            DataType::DBR_LONG => 0x05,
+            DataType::DBR_DOUBLE => 0x06,
+            DataType::DBR_STS_STRING => 0x07,
+            DataType::DBR_STS_SHORT => 0x08,
+            DataType::DBR_STS_FLOAT => 0x09,
+            DataType::DBR_STS_ENUM => 0x0A,
+            DataType::DBR_STS_CHAR => 0x0B,
+            DataType::DBR_STS_LONG => 0x0C,
+            DataType::DBR_STS_DOUBLE => 0x0D,
+            DataType::DBR_TIME_STRING => 0x0E,
+            DataType::DBR_TIME_SHORT => 0x0F,
+            DataType::DBR_TIME_FLOAT => 0x10,
+            DataType::DBR_TIME_ENUM => 0x11,
+            DataType::DBR_TIME_CHAR => 0x12,
+            DataType::DBR_TIME_LONG => 0x13,
+            DataType::DBR_TIME_DOUBLE => 0x14,
+            DataType::DBR_GR_STRING => 0x15,
+            DataType::DBR_GR_SHORT => 0x16,
+            DataType::DBR_GR_INT => 0x16,
+            DataType::DBR_GR_FLOAT => 0x17,
+            DataType::DBR_GR_ENUM => 0x18,
+            DataType::DBR_GR_CHAR => 0x19,
+            DataType::DBR_GR_LONG => 0x1A,
+            DataType::DBR_GR_DOUBLE => 0x1B,
+            DataType::DBR_CTRL_STRING => 0x1C,
+            DataType::DBR_CTRL_SHORT => 0x1D,
+            DataType::DBR_CTRL_INT => 0x1D,
+            DataType::DBR_CTRL_FLOAT => 0x1E,
+            DataType::DBR_CTRL_ENUM => 0x1F,
+            DataType::DBR_CTRL_CHAR => 0x20,
+            DataType::DBR_CTRL_LONG => 0x21,
+            DataType::DBR_CTRL_DOUBLE => 0x22,
+            DataType::DBR_PUT_ACKT => 0x23,
+            DataType::DBR_PUT_ACKS => 0x24,
+            DataType::DBR_STSACK_STRING => 0x25,
+            DataType::DBR_CLASS_NAME => 0x26,
+        }
+    }
+}
+impl std::convert::TryFrom<u16> for DataType {
+    type Error = Error;
+
+    /// Parses a data type ID. The SHORT variants are returned for IDs shared with their INT aliases.
+    fn try_from(val: u16) -> Result<Self, Error> {
+        Ok(match val {
+            0x00 => DataType::DBR_STRING,
+            0x01 => DataType::DBR_SHORT,
+            0x02 => DataType::DBR_FLOAT,
+            0x03 => DataType::DBR_ENUM,
+            0x04 => DataType::DBR_CHAR,
+            0x05 => DataType::DBR_LONG,
+            0x06 => DataType::DBR_DOUBLE,
+            0x07 => DataType::DBR_STS_STRING,
+            0x08 => DataType::DBR_STS_SHORT,
+            0x09 => DataType::DBR_STS_FLOAT,
+            0x0A => DataType::DBR_STS_ENUM,
+            0x0B => DataType::DBR_STS_CHAR,
+            0x0C => DataType::DBR_STS_LONG,
+            0x0D => DataType::DBR_STS_DOUBLE,
+            0x0E => DataType::DBR_TIME_STRING,
+            0x0F => DataType::DBR_TIME_SHORT,
+            0x10 => DataType::DBR_TIME_FLOAT,
+            0x11 => DataType::DBR_TIME_ENUM,
+            0x12 => DataType::DBR_TIME_CHAR,
+            0x13 => DataType::DBR_TIME_LONG,
+            0x14 => DataType::DBR_TIME_DOUBLE,
+            0x15 => DataType::DBR_GR_STRING,
+            0x16 => DataType::DBR_GR_SHORT,
+            0x17 => DataType::DBR_GR_FLOAT,
+            0x18 => DataType::DBR_GR_ENUM,
+            0x19 => DataType::DBR_GR_CHAR,
+            0x1A => DataType::DBR_GR_LONG,
+            0x1B => DataType::DBR_GR_DOUBLE,
+            0x1C => DataType::DBR_CTRL_STRING,
+            0x1D => DataType::DBR_CTRL_SHORT,
+            0x1E => DataType::DBR_CTRL_FLOAT,
+            0x1F => DataType::DBR_CTRL_ENUM,
+            0x20 => DataType::DBR_CTRL_CHAR,
+            0x21 => DataType::DBR_CTRL_LONG,
+            0x22 => DataType::DBR_CTRL_DOUBLE,
+            0x23 => DataType::DBR_PUT_ACKT,
+            0x24 => DataType::DBR_PUT_ACKS,
+            0x25 => DataType::DBR_STSACK_STRING,
+            0x26 => DataType::DBR_CLASS_NAME,
+
+            _ => return Err(Error::ParseError("Value is not a valid data type ID".into()))
+        })
+    }
 }
 
 pub struct MessageHeader {
@@ -279,10 +415,14 @@ pub struct MessageHeader {
     pub parameter_2: u32,
 }
 impl MessageHeader {
+    /// Parses a 16-byte header. Fails if the header carries the extended message marker; use [`Header::from_bytes`] to accept both forms.
     pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
         if buf.len() != HEADER_SIZE {
             return Err(Error::BufferError(format!("Expected {}-byte buffer", HEADER_SIZE)))
         }
+        if buf[2..4] == EXTENDED_MARKER.to_be_bytes() {
+            return Err(Error::ParseError("Header is an extended message header".into()))
+        }
         Ok(Self {
             command:      u16::from_be_bytes(buf[0..2].try_into().unwrap()),
             payload_size: u16::from_be_bytes(buf[2..4].try_into().unwrap()),
@@ -322,9 +462,774 @@ pub struct ExtendedMessageHeader {
     /// Command-dependent parameter
     pub parameter_2: u32,
 
-    /// Size of the payload (in bytes). Must not exceed 0x4000. Value of 0xFFFF indicates extended message.
+    /// Size of the payload (in bytes).
     pub payload_size: u32,
 
     /// Number of elements in the payload.
     pub data_count: u32,
 }
+impl ExtendedMessageHeader {
+    /// Parses a 24-byte extended header, validating the extended message markers.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() != EXTENDED_HEADER_SIZE {
+            return Err(Error::BufferError(format!("Expected {}-byte buffer", EXTENDED_HEADER_SIZE)))
+        }
+        if buf[2..4] != EXTENDED_MARKER.to_be_bytes() || buf[6..8] != [0, 0] {
+            return Err(Error::ParseError("Header is missing the extended message marker".into()))
+        }
+        Ok(Self {
+            command:      u16::from_be_bytes(buf[0..2].try_into().unwrap()),
+            data_type:    u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            parameter_1:  u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            parameter_2:  u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+            payload_size: u32::from_be_bytes(buf[16..20].try_into().unwrap()),
+            data_count:   u32::from_be_bytes(buf[20..].try_into().unwrap()),
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(EXTENDED_HEADER_SIZE);
+        buf.append(&mut self.command.to_be_bytes().to_vec());
+        buf.append(&mut EXTENDED_MARKER.to_be_bytes().to_vec());
+        buf.append(&mut self.data_type.to_be_bytes().to_vec());
+        buf.append(&mut 0u16.to_be_bytes().to_vec());
+        buf.append(&mut self.parameter_1.to_be_bytes().to_vec());
+        buf.append(&mut self.parameter_2.to_be_bytes().to_vec());
+        buf.append(&mut self.payload_size.to_be_bytes().to_vec());
+        buf.append(&mut self.data_count.to_be_bytes().to_vec());
+
+        buf
+    }
+}
+
+/// Either form of message header, as found at the start of every message.
+pub enum Header {
+    Standard(MessageHeader),
+    Extended(ExtendedMessageHeader),
+}
+impl Header {
+    /// Builds a header, choosing the extended form when the payload size or element count do not fit in 16 bits.
+    pub fn new(command: u16, payload_size: u32, data_type: u16, data_count: u32, parameter_1: u32, parameter_2: u32) -> Self {
+        if payload_size >= EXTENDED_MARKER as u32 || data_count >= EXTENDED_MARKER as u32 {
+            Header::Extended(ExtendedMessageHeader { command, data_type, parameter_1, parameter_2, payload_size, data_count })
+        } else {
+            Header::Standard(MessageHeader {
+                command,
+                payload_size: payload_size as u16,
+                data_type,
+                data_count: data_count as u16,
+                parameter_1,
+                parameter_2,
+            })
+        }
+    }
+
+    /// Parses the header at the start of `buf`, switching to the 24-byte form when the extended marker is present.
+    /// Trailing bytes after the header are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::BufferError(format!("Expected at least {} bytes, got {}", HEADER_SIZE, buf.len())))
+        }
+        if buf[2..4] != EXTENDED_MARKER.to_be_bytes() {
+            return Ok(Header::Standard(MessageHeader::from_bytes(&buf[..HEADER_SIZE])?))
+        }
+        if buf.len() < EXTENDED_HEADER_SIZE {
+            return Err(Error::BufferError(format!("Expected at least {} bytes, got {}", EXTENDED_HEADER_SIZE, buf.len())))
+        }
+        Ok(Header::Extended(ExtendedMessageHeader::from_bytes(&buf[..EXTENDED_HEADER_SIZE])?))
+    }
+
+    /// Returns true if the first 16 bytes of a message announce an extended header.
+    pub fn is_extended(buf: &[u8]) -> bool {
+        buf.len() >= 4 && buf[2..4] == EXTENDED_MARKER.to_be_bytes()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        match self {
+            Header::Standard(header) => header.as_bytes(),
+            Header::Extended(header) => header.as_bytes(),
+        }
+    }
+
+    /// Size of the encoded header in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Header::Standard(_) => HEADER_SIZE,
+            Header::Extended(_) => EXTENDED_HEADER_SIZE,
+        }
+    }
+
+    pub fn command(&self) -> u16 {
+        match self {
+            Header::Standard(header) => header.command,
+            Header::Extended(header) => header.command,
+        }
+    }
+
+    pub fn payload_size(&self) -> u32 {
+        match self {
+            Header::Standard(header) => header.payload_size as u32,
+            Header::Extended(header) => header.payload_size,
+        }
+    }
+
+    pub fn data_type(&self) -> u16 {
+        match self {
+            Header::Standard(header) => header.data_type,
+            Header::Extended(header) => header.data_type,
+        }
+    }
+
+    pub fn data_count(&self) -> u32 {
+        match self {
+            Header::Standard(header) => header.data_count as u32,
+            Header::Extended(header) => header.data_count,
+        }
+    }
+
+    pub fn parameter_1(&self) -> u32 {
+        match self {
+            Header::Standard(header) => header.parameter_1,
+            Header::Extended(header) => header.parameter_1,
+        }
+    }
+
+    pub fn parameter_2(&self) -> u32 {
+        match self {
+            Header::Standard(header) => header.parameter_2,
+            Header::Extended(header) => header.parameter_2,
+        }
+    }
+}
+
+/// ECA status codes carried in responses, as defined in caerr.h.
+pub mod eca {
+    pub const ECA_NORMAL: u32 = 1;
+    pub const ECA_ALLOCMEM: u32 = 48;
+    pub const ECA_TOLARGE: u32 = 72;
+    pub const ECA_TIMEOUT: u32 = 80;
+    pub const ECA_BADTYPE: u32 = 114;
+    pub const ECA_INTERNAL: u32 = 142;
+    pub const ECA_GETFAIL: u32 = 152;
+    pub const ECA_PUTFAIL: u32 = 160;
+    pub const ECA_BADCOUNT: u32 = 176;
+    pub const ECA_BADSTR: u32 = 186;
+    pub const ECA_DISCONN: u32 = 192;
+    pub const ECA_DBLCHNL: u32 = 200;
+    pub const ECA_EVDISALLOW: u32 = 210;
+    pub const ECA_BADMONID: u32 = 242;
+    pub const ECA_BADMASK: u32 = 330;
+    pub const ECA_IODONE: u32 = 339;
+    pub const ECA_IOINPROGRESS: u32 = 347;
+    pub const ECA_BADSYNCGRP: u32 = 354;
+    pub const ECA_PUTCBINPROG: u32 = 362;
+    pub const ECA_NORDACCESS: u32 = 368;
+    pub const ECA_NOWTACCESS: u32 = 376;
+    pub const ECA_ANACHRONISM: u32 = 386;
+    pub const ECA_NOSEARCHADDR: u32 = 392;
+    pub const ECA_NOCONVERT: u32 = 400;
+    pub const ECA_BADCHID: u32 = 410;
+    pub const ECA_BADFUNCPTR: u32 = 418;
+    pub const ECA_ISATTACHED: u32 = 424;
+    pub const ECA_UNAVAILINSERV: u32 = 432;
+    pub const ECA_CHANDESTROY: u32 = 440;
+    pub const ECA_BADPRIORITY: u32 = 450;
+    pub const ECA_NOTTHREADED: u32 = 458;
+    pub const ECA_16KARRAYCLIENT: u32 = 464;
+    pub const ECA_CONNSEQTMO: u32 = 472;
+    pub const ECA_UNRESPTMO: u32 = 480;
+
+    /// Returns true if the status code reports success.
+    pub fn is_success(status: u32) -> bool {
+        status & 1 == 1
+    }
+
+    /// Returns the libca message text for a status code.
+    pub fn message(status: u32) -> &'static str {
+        match status {
+            ECA_NORMAL => "Normal successful completion",
+            ECA_ALLOCMEM => "Unable to allocate additional dynamic memory",
+            ECA_TOLARGE => "The requested data transfer is greater than available memory or EPICS_CA_MAX_ARRAY_BYTES",
+            ECA_TIMEOUT => "User specified timeout on IO operation expired",
+            ECA_BADTYPE => "The data type specified is invalid",
+            ECA_INTERNAL => "Channel Access Internal Failure",
+            ECA_GETFAIL => "Data conversion between client's type and the server's type failed",
+            ECA_PUTFAIL => "Channel write request failed",
+            ECA_BADCOUNT => "Invalid element count requested",
+            ECA_BADSTR => "Invalid string",
+            ECA_DISCONN => "Virtual circuit disconnect",
+            ECA_DBLCHNL => "Identical process variable names on multiple servers",
+            ECA_EVDISALLOW => "Request inappropriate within subscription (monitor) update callback",
+            ECA_BADMONID => "Bad event subscription (monitor) identifier",
+            ECA_BADMASK => "Invalid event selection mask",
+            ECA_IODONE => "IO operations have completed",
+            ECA_IOINPROGRESS => "IO operations are in progress",
+            ECA_BADSYNCGRP => "Invalid synchronous group identifier",
+            ECA_PUTCBINPROG => "Put callback timed out",
+            ECA_NORDACCESS => "Read access denied",
+            ECA_NOWTACCESS => "Write access denied",
+            ECA_ANACHRONISM => "Requested feature is no longer supported",
+            ECA_NOSEARCHADDR => "Empty PV search address list",
+            ECA_NOCONVERT => "No reasonable data conversion between client and server types",
+            ECA_BADCHID => "Invalid channel identifier",
+            ECA_BADFUNCPTR => "Invalid function pointer",
+            ECA_ISATTACHED => "Thread is already attached to a client context",
+            ECA_UNAVAILINSERV => "Not supported by attached service",
+            ECA_CHANDESTROY => "User destroyed channel",
+            ECA_BADPRIORITY => "Invalid channel priority",
+            ECA_NOTTHREADED => "Preemptive callback not enabled - additional threads may not join context",
+            ECA_16KARRAYCLIENT => "Client's protocol revision does not support transfers exceeding 16k bytes",
+            ECA_CONNSEQTMO => "Virtual circuit connection sequence aborted",
+            ECA_UNRESPTMO => "Virtual circuit unresponsive",
+            _ => "Unknown status code",
+        }
+    }
+}
+
+/// Value of the CA_PROTO_SEARCH data type field asking the server to answer with CA_PROTO_NOT_FOUND.
+pub const DO_REPLY: u16 = 10;
+/// Value of the CA_PROTO_SEARCH data type field asking the server to stay silent when the name is unknown.
+pub const DONT_REPLY: u16 = 5;
+
+/// CA_PROTO_EVENT_ADD mask bit selecting value changes beyond the monitor deadband.
+pub const DBE_VALUE: u16 = 1;
+/// CA_PROTO_EVENT_ADD mask bit selecting value changes beyond the archive deadband.
+pub const DBE_LOG: u16 = 2;
+/// CA_PROTO_EVENT_ADD mask bit selecting alarm state changes.
+pub const DBE_ALARM: u16 = 4;
+/// CA_PROTO_EVENT_ADD mask bit selecting property (metadata) changes.
+pub const DBE_PROPERTY: u16 = 8;
+
+/// Permissions granted to a client on a channel, carried by CA_PROTO_ACCESS_RIGHTS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccessRights {
+    pub read: bool,
+    pub write: bool,
+}
+impl AccessRights {
+    /// CA_PROTO_ACCESS_RIGHTS bit granting read access.
+    pub const READ: u32 = 1;
+    /// CA_PROTO_ACCESS_RIGHTS bit granting write access.
+    pub const WRITE: u32 = 2;
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self { read: bits & Self::READ != 0, write: bits & Self::WRITE != 0 }
+    }
+
+    pub fn bits(self) -> u32 {
+        (if self.read { Self::READ } else { 0 }) | (if self.write { Self::WRITE } else { 0 })
+    }
+}
+
+/// Size of the CA_PROTO_EVENT_ADD request payload (three deprecated floats, the event mask and padding).
+const EVENT_ADD_PAYLOAD_SIZE: usize = 16;
+
+/// The side of a conversation a message was sent from.
+///
+/// Several commands share an ID between the request and its response but lay out their fields differently,
+/// so decoding needs to know which side produced the bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Sent by a client (or to the repeater).
+    Client,
+    /// Sent by a server (or forwarded by the repeater).
+    Server,
+}
+
+/// A fully decoded Channel Access message.
+///
+/// Requests and responses that share a command ID but carry different fields are separate variants.
+/// Payloads of DBR values are kept as raw big-endian bytes together with their data type and element count.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// CA_PROTO_VERSION
+    Version { priority: u16, minor_version: u16 },
+
+    /// CA_PROTO_SEARCH request
+    Search { reply: bool, minor_version: u16, cid: u32, name: String },
+
+    /// CA_PROTO_SEARCH response. `server_ip` is 0xFFFFFFFF when the reply's source address should be used.
+    SearchResponse { port: u16, server_ip: u32, cid: u32, minor_version: u16 },
+
+    /// CA_PROTO_NOT_FOUND
+    NotFound { minor_version: u16, cid: u32 },
+
+    /// CA_PROTO_RSRV_IS_UP
+    RsrvIsUp { minor_version: u16, port: u16, beacon_id: u32, address: u32 },
+
+    /// CA_REPEATER_CONFIRM
+    RepeaterConfirm { address: u32 },
+
+    /// CA_REPEATER_REGISTER
+    RepeaterRegister { address: u32 },
+
+    /// CA_PROTO_EVENT_ADD request
+    EventAdd { data_type: u16, data_count: u32, sid: u32, subscription_id: u32, mask: u16 },
+
+    /// CA_PROTO_EVENT_ADD response. An empty payload confirms a CA_PROTO_EVENT_CANCEL.
+    EventAddResponse { data_type: u16, data_count: u32, status: u32, subscription_id: u32, payload: Vec<u8> },
+
+    /// CA_PROTO_EVENT_CANCEL
+    EventCancel { data_type: u16, data_count: u32, sid: u32, subscription_id: u32 },
+
+    /// CA_PROTO_READ. The payload is only present in responses.
+    Read { data_type: u16, data_count: u32, sid: u32, ioid: u32, payload: Vec<u8> },
+
+    /// CA_PROTO_WRITE
+    Write { data_type: u16, data_count: u32, sid: u32, ioid: u32, payload: Vec<u8> },
+
+    /// CA_PROTO_SNAPSHOT
+    Snapshot,
+
+    /// CA_PROTO_BUILD
+    Build,
+
+    /// CA_PROTO_EVENTS_OFF
+    EventsOff,
+
+    /// CA_PROTO_EVENTS_ON
+    EventsOn,
+
+    /// CA_PROTO_READ_SYNC
+    ReadSync,
+
+    /// CA_PROTO_CLEAR_CHANNEL
+    ClearChannel { sid: u32, cid: u32 },
+
+    /// CA_PROTO_READ_NOTIFY request
+    ReadNotify { data_type: u16, data_count: u32, sid: u32, ioid: u32 },
+
+    /// CA_PROTO_READ_NOTIFY response. `status` carries an ECA status code.
+    ReadNotifyResponse { data_type: u16, data_count: u32, status: u32, ioid: u32, payload: Vec<u8> },
+
+    /// CA_PROTO_READ_BUILD
+    ReadBuild,
+
+    /// CA_PROTO_CREATE_CHAN request
+    CreateChan { cid: u32, minor_version: u32, name: String },
+
+    /// CA_PROTO_CREATE_CHAN response
+    CreateChanResponse { data_type: u16, data_count: u32, cid: u32, sid: u32 },
+
+    /// CA_PROTO_WRITE_NOTIFY request
+    WriteNotify { data_type: u16, data_count: u32, sid: u32, ioid: u32, payload: Vec<u8> },
+
+    /// CA_PROTO_WRITE_NOTIFY response. `status` carries an ECA status code.
+    WriteNotifyResponse { data_type: u16, data_count: u32, status: u32, ioid: u32 },
+
+    /// CA_PROTO_CLIENT_NAME
+    ClientName { name: String },
+
+    /// CA_PROTO_HOST_NAME
+    HostName { name: String },
+
+    /// CA_PROTO_ACCESS_RIGHTS
+    AccessRights { cid: u32, access_rights: u32 },
+
+    /// CA_PROTO_ECHO
+    Echo,
+
+    /// CA_PROTO_SIGNAL
+    Signal,
+
+    /// CA_PROTO_CREATE_CH_FAIL
+    CreateChFail { cid: u32 },
+
+    /// CA_PROTO_SERVER_DISCONN
+    ServerDisconn { cid: u32 },
+}
+impl Message {
+    /// Returns the command this message is sent as.
+    pub fn command(&self) -> Command {
+        match self {
+            Message::Version { .. } => Command::CA_PROTO_VERSION,
+            Message::Search { .. } | Message::SearchResponse { .. } => Command::CA_PROTO_SEARCH,
+            Message::NotFound { .. } => Command::CA_PROTO_NOT_FOUND,
+            Message::RsrvIsUp { .. } => Command::CA_PROTO_RSRV_IS_UP,
+            Message::RepeaterConfirm { .. } => Command::CA_REPEATER_CONFIRM,
+            Message::RepeaterRegister { .. } => Command::CA_REPEATER_REGISTER,
+            Message::EventAdd { .. } | Message::EventAddResponse { .. } => Command::CA_PROTO_EVENT_ADD,
+            Message::EventCancel { .. } => Command::CA_PROTO_EVENT_CANCEL,
+            Message::Read { .. } => Command::CA_PROTO_READ,
+            Message::Write { .. } => Command::CA_PROTO_WRITE,
+            Message::Snapshot => Command::CA_PROTO_SNAPSHOT,
+            Message::Build => Command::CA_PROTO_BUILD,
+            Message::EventsOff => Command::CA_PROTO_EVENTS_OFF,
+            Message::EventsOn => Command::CA_PROTO_EVENTS_ON,
+            Message::ReadSync => Command::CA_PROTO_READ_SYNC,
+            Message::ClearChannel { .. } => Command::CA_PROTO_CLEAR_CHANNEL,
+            Message::ReadNotify { .. } | Message::ReadNotifyResponse { .. } => Command::CA_PROTO_READ_NOTIFY,
+            Message::ReadBuild => Command::CA_PROTO_READ_BUILD,
+            Message::CreateChan { .. } | Message::CreateChanResponse { .. } => Command::CA_PROTO_CREATE_CHAN,
+            Message::WriteNotify { .. } | Message::WriteNotifyResponse { .. } => Command::CA_PROTO_WRITE_NOTIFY,
+            Message::ClientName { .. } => Command::CA_PROTO_CLIENT_NAME,
+            Message::HostName { .. } => Command::CA_PROTO_HOST_NAME,
+            Message::AccessRights { .. } => Command::CA_PROTO_ACCESS_RIGHTS,
+            Message::Echo => Command::CA_PROTO_ECHO,
+            Message::Signal => Command::CA_PROTO_SIGNAL,
+            Message::CreateChFail { .. } => Command::CA_PROTO_CREATE_CH_FAIL,
+            Message::ServerDisconn { .. } => Command::CA_PROTO_SERVER_DISCONN,
+        }
+    }
+
+    /// Decodes the message at the start of `buf`, returning it along with the number of bytes consumed.
+    ///
+    /// `origin` selects between request and response layouts for commands that use both.
+    pub fn from_bytes(buf: &[u8], origin: Origin) -> Result<(Self, usize), Error> {
+        let header = Header::from_bytes(buf)?;
+        let size = header.size() + header.payload_size() as usize;
+        if buf.len() < size {
+            return Err(Error::BufferError(format!("Expected {}-byte message, got {} bytes", size, buf.len())))
+        }
+        let payload = &buf[header.size()..size];
+
+        let message = match (Command::try_from(header.command())?, origin) {
+            (Command::CA_PROTO_VERSION, _) => Message::Version {
+                priority: header.data_type(),
+                minor_version: header.data_count() as u16,
+            },
+            (Command::CA_PROTO_SEARCH, Origin::Client) => Message::Search {
+                reply: header.data_type() == DO_REPLY,
+                minor_version: header.data_count() as u16,
+                cid: header.parameter_1(),
+                name: decode_string(payload)?,
+            },
+            (Command::CA_PROTO_SEARCH, Origin::Server) => Message::SearchResponse {
+                port: header.data_type(),
+                server_ip: header.parameter_1(),
+                cid: header.parameter_2(),
+                minor_version: match payload.get(0..2) {
+                    Some(bytes) => u16::from_be_bytes(bytes.try_into().unwrap()),
+                    None => return Err(Error::ParseError("Search response is missing the server minor version".into())),
+                },
+            },
+            (Command::CA_PROTO_NOT_FOUND, _) => Message::NotFound {
+                minor_version: header.data_count() as u16,
+                cid: header.parameter_1(),
+            },
+            (Command::CA_PROTO_RSRV_IS_UP, _) => Message::RsrvIsUp {
+                minor_version: header.data_type(),
+                port: header.data_count() as u16,
+                beacon_id: header.parameter_1(),
+                address: header.parameter_2(),
+            },
+            (Command::CA_REPEATER_CONFIRM, _) => Message::RepeaterConfirm { address: header.parameter_2() },
+            (Command::CA_REPEATER_REGISTER, _) => Message::RepeaterRegister { address: header.parameter_2() },
+            (Command::CA_PROTO_EVENT_ADD, Origin::Client) => {
+                if payload.len() < EVENT_ADD_PAYLOAD_SIZE {
+                    return Err(Error::ParseError("Event add request is missing the event mask".into()))
+                }
+                Message::EventAdd {
+                    data_type: header.data_type(),
+                    data_count: header.data_count(),
+                    sid: header.parameter_1(),
+                    subscription_id: header.parameter_2(),
+                    mask: u16::from_be_bytes(payload[12..14].try_into().unwrap()),
+                }
+            },
+            (Command::CA_PROTO_EVENT_ADD, Origin::Server) => Message::EventAddResponse {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                status: header.parameter_1(),
+                subscription_id: header.parameter_2(),
+                payload: payload.to_vec(),
+            },
+            (Command::CA_PROTO_EVENT_CANCEL, _) => Message::EventCancel {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                sid: header.parameter_1(),
+                subscription_id: header.parameter_2(),
+            },
+            (Command::CA_PROTO_READ, _) => Message::Read {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                sid: header.parameter_1(),
+                ioid: header.parameter_2(),
+                payload: payload.to_vec(),
+            },
+            (Command::CA_PROTO_WRITE, _) => Message::Write {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                sid: header.parameter_1(),
+                ioid: header.parameter_2(),
+                payload: payload.to_vec(),
+            },
+            (Command::CA_PROTO_SNAPSHOT, _) => Message::Snapshot,
+            (Command::CA_PROTO_BUILD, _) => Message::Build,
+            (Command::CA_PROTO_EVENTS_OFF, _) => Message::EventsOff,
+            (Command::CA_PROTO_EVENTS_ON, _) => Message::EventsOn,
+            (Command::CA_PROTO_READ_SYNC, _) => Message::ReadSync,
+            (Command::CA_PROTO_CLEAR_CHANNEL, _) => Message::ClearChannel {
+                sid: header.parameter_1(),
+                cid: header.parameter_2(),
+            },
+            (Command::CA_PROTO_READ_NOTIFY, Origin::Client) => Message::ReadNotify {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                sid: header.parameter_1(),
+                ioid: header.parameter_2(),
+            },
+            (Command::CA_PROTO_READ_NOTIFY, Origin::Server) => Message::ReadNotifyResponse {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                status: header.parameter_1(),
+                ioid: header.parameter_2(),
+                payload: payload.to_vec(),
+            },
+            (Command::CA_PROTO_READ_BUILD, _) => Message::ReadBuild,
+            (Command::CA_PROTO_CREATE_CHAN, Origin::Client) => Message::CreateChan {
+                cid: header.parameter_1(),
+                minor_version: header.parameter_2(),
+                name: decode_string(payload)?,
+            },
+            (Command::CA_PROTO_CREATE_CHAN, Origin::Server) => Message::CreateChanResponse {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                cid: header.parameter_1(),
+                sid: header.parameter_2(),
+            },
+            (Command::CA_PROTO_WRITE_NOTIFY, Origin::Client) => Message::WriteNotify {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                sid: header.parameter_1(),
+                ioid: header.parameter_2(),
+                payload: payload.to_vec(),
+            },
+            (Command::CA_PROTO_WRITE_NOTIFY, Origin::Server) => Message::WriteNotifyResponse {
+                data_type: header.data_type(),
+                data_count: header.data_count(),
+                status: header.parameter_1(),
+                ioid: header.parameter_2(),
+            },
+            (Command::CA_PROTO_CLIENT_NAME, _) => Message::ClientName { name: decode_string(payload)? },
+            (Command::CA_PROTO_HOST_NAME, _) => Message::HostName { name: decode_string(payload)? },
+            (Command::CA_PROTO_ACCESS_RIGHTS, _) => Message::AccessRights {
+                cid: header.parameter_1(),
+                access_rights: header.parameter_2(),
+            },
+            (Command::CA_PROTO_ECHO, _) => Message::Echo,
+            (Command::CA_PROTO_SIGNAL, _) => Message::Signal,
+            (Command::CA_PROTO_CREATE_CH_FAIL, _) => Message::CreateChFail { cid: header.parameter_1() },
+            (Command::CA_PROTO_SERVER_DISCONN, _) => Message::ServerDisconn { cid: header.parameter_1() },
+        };
+
+        Ok((message, size))
+    }
+
+    /// Decodes every message in `buf`, such as a datagram holding several messages back to back.
+    pub fn all_from_bytes(buf: &[u8], origin: Origin) -> Result<Vec<Self>, Error> {
+        let mut messages = vec!();
+        let mut position = 0;
+        while position < buf.len() {
+            let (message, size) = Message::from_bytes(&buf[position..], origin)?;
+            messages.push(message);
+            position += size;
+        }
+        Ok(messages)
+    }
+
+    /// Encodes the message into a header followed by its payload padded to an 8-byte boundary.
+    /// The extended header form is used automatically for large payloads and element counts.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let command: u16 = self.command().into();
+        let (data_type, data_count, parameter_1, parameter_2, payload) = match self {
+            Message::Version { priority, minor_version } => (*priority, *minor_version as u32, 0, 0, vec!()),
+            Message::Search { reply, minor_version, cid, name } => {
+                let reply = if *reply { DO_REPLY } else { DONT_REPLY };
+                (reply, *minor_version as u32, *cid, *cid, encode_string(name))
+            },
+            Message::SearchResponse { port, server_ip, cid, minor_version } => {
+                (*port, 0, *server_ip, *cid, minor_version.to_be_bytes().to_vec())
+            },
+            Message::NotFound { minor_version, cid } => (DO_REPLY, *minor_version as u32, *cid, *cid, vec!()),
+            Message::RsrvIsUp { minor_version, port, beacon_id, address } => (*minor_version, *port as u32, *beacon_id, *address, vec!()),
+            Message::RepeaterConfirm { address } => (0, 0, 0, *address, vec!()),
+            Message::RepeaterRegister { address } => (0, 0, 0, *address, vec!()),
+            Message::EventAdd { data_type, data_count, sid, subscription_id, mask } => {
+                let mut payload = vec![0u8; EVENT_ADD_PAYLOAD_SIZE];
+                payload[12..14].copy_from_slice(&mask.to_be_bytes());
+                (*data_type, *data_count, *sid, *subscription_id, payload)
+            },
+            Message::EventAddResponse { data_type, data_count, status, subscription_id, payload } => {
+                (*data_type, *data_count, *status, *subscription_id, payload.clone())
+            },
+            Message::EventCancel { data_type, data_count, sid, subscription_id } => (*data_type, *data_count, *sid, *subscription_id, vec!()),
+            Message::Read { data_type, data_count, sid, ioid, payload } => (*data_type, *data_count, *sid, *ioid, payload.clone()),
+            Message::Write { data_type, data_count, sid, ioid, payload } => (*data_type, *data_count, *sid, *ioid, payload.clone()),
+            Message::Snapshot
+            | Message::Build
+            | Message::EventsOff
+            | Message::EventsOn
+            | Message::ReadSync
+            | Message::ReadBuild
+            | Message::Echo
+            | Message::Signal => (0, 0, 0, 0, vec!()),
+            Message::ClearChannel { sid, cid } => (0, 0, *sid, *cid, vec!()),
+            Message::ReadNotify { data_type, data_count, sid, ioid } => (*data_type, *data_count, *sid, *ioid, vec!()),
+            Message::ReadNotifyResponse { data_type, data_count, status, ioid, payload } => {
+                (*data_type, *data_count, *status, *ioid, payload.clone())
+            },
+            Message::CreateChan { cid, minor_version, name } => (0, 0, *cid, *minor_version, encode_string(name)),
+            Message::CreateChanResponse { data_type, data_count, cid, sid } => (*data_type, *data_count, *cid, *sid, vec!()),
+            Message::WriteNotify { data_type, data_count, sid, ioid, payload } => (*data_type, *data_count, *sid, *ioid, payload.clone()),
+            Message::WriteNotifyResponse { data_type, data_count, status, ioid } => (*data_type, *data_count, *status, *ioid, vec!()),
+            Message::ClientName { name } => (0, 0, 0, 0, encode_string(name)),
+            Message::HostName { name } => (0, 0, 0, 0, encode_string(name)),
+            Message::AccessRights { cid, access_rights } => (0, 0, *cid, *access_rights, vec!()),
+            Message::CreateChFail { cid } => (0, 0, *cid, 0, vec!()),
+            Message::ServerDisconn { cid } => (0, 0, *cid, 0, vec!()),
+        };
+        let payload = pad_payload(payload);
+
+        let header = Header::new(command, payload.len() as u32, data_type, data_count, parameter_1, parameter_2);
+        let mut buf = header.as_bytes();
+        buf.extend_from_slice(&payload);
+
+        buf
+    }
+}
+
+/// Pads a payload with zeros up to the next 8-byte boundary, as required for every CA message.
+pub fn pad_payload(mut payload: Vec<u8>) -> Vec<u8> {
+    let padded = (payload.len() + 7) & !7;
+    payload.resize(padded, 0);
+    payload
+}
+
+/// Encodes a string as a null-terminated payload.
+fn encode_string(value: &str) -> Vec<u8> {
+    let mut buf = value.as_bytes().to_vec();
+    buf.push(0);
+    buf
+}
+
+/// Decodes a null-terminated (and possibly padded) string payload.
+fn decode_string(buf: &[u8]) -> Result<String, Error> {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..end].to_vec()).map_err(|e| Error::ParseError(format!("Invalid string payload: {:?}", e)))
+}
+
+/// Reassembles the messages of a TCP stream from bytes received in arbitrary pieces.
+///
+/// Payloads above the size limit are skipped without being buffered.
+pub struct MessageReader {
+    origin: Origin,
+    max_payload_size: usize,
+    buf: Vec<u8>,
+    /// Bytes of an oversized payload still to be skipped.
+    discard: usize,
+}
+impl MessageReader {
+    pub fn new(origin: Origin, max_payload_size: usize) -> Self {
+        Self { origin, max_payload_size, buf: vec!(), discard: 0 }
+    }
+
+    /// Appends received bytes and decodes every message they complete. Undecodable messages are skipped.
+    /// Fails on an invalid header, after which the stream cannot be followed any more.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Message>, Error> {
+        let skipped = self.discard.min(data.len());
+        self.discard -= skipped;
+        self.buf.extend_from_slice(&data[skipped..]);
+
+        let mut messages = vec!();
+        loop {
+            if self.buf.len() < HEADER_SIZE || (Header::is_extended(&self.buf) && self.buf.len() < EXTENDED_HEADER_SIZE) {
+                return Ok(messages)
+            }
+            let header = Header::from_bytes(&self.buf)?;
+            let header_size = header.size();
+            let payload_size = header.payload_size() as usize;
+
+            if payload_size > self.max_payload_size {
+                error!("Discarding {}-byte payload exceeding EPICS_CA_MAX_ARRAY_BYTES", payload_size);
+                let available = (self.buf.len() - header_size).min(payload_size);
+                self.discard = payload_size - available;
+                self.buf.drain(..header_size + available);
+                continue;
+            }
+
+            let size = header_size + payload_size;
+            if self.buf.len() < size {
+                return Ok(messages)
+            }
+            match Message::from_bytes(&self.buf[..size], self.origin) {
+                Ok((message, _)) => messages.push(message),
+                Err(e) => warn!("Discarding undecodable message: {:?}", e),
+            }
+            self.buf.drain(..size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(message: Message, origin: Origin) {
+        let buf = message.as_bytes();
+        assert_eq!(buf.len() % 8, 0);
+
+        let (decoded, size) = Message::from_bytes(&buf, origin).unwrap();
+        assert_eq!(size, buf.len());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        roundtrip(Message::Version { priority: 0, minor_version: crate::MINOR_PROTOCOL_VERSION }, Origin::Client);
+        roundtrip(Message::Search { reply: true, minor_version: 11, cid: 7, name: "PV:NAME".into() }, Origin::Client);
+        roundtrip(Message::SearchResponse { port: 5064, server_ip: 0xFFFFFFFF, cid: 7, minor_version: 13 }, Origin::Server);
+        roundtrip(Message::EventAdd { data_type: 6, data_count: 1, sid: 3, subscription_id: 9, mask: 5 }, Origin::Client);
+        roundtrip(Message::ReadNotifyResponse { data_type: 6, data_count: 1, status: 1, ioid: 2, payload: vec![0x40, 0x09, 0x21, 0xfb, 0x54, 0x44, 0x2d, 0x18] }, Origin::Server);
+        roundtrip(Message::CreateChan { cid: 1, minor_version: 11, name: "PV:NAME".into() }, Origin::Client);
+        roundtrip(Message::CreateChanResponse { data_type: 6, data_count: 1, cid: 1, sid: 42 }, Origin::Server);
+        roundtrip(Message::AccessRights { cid: 1, access_rights: 3 }, Origin::Server);
+        assert_eq!(AccessRights::from_bits(3), AccessRights { read: true, write: true });
+        assert_eq!(AccessRights { read: false, write: true }.bits(), AccessRights::WRITE);
+        roundtrip(Message::Echo, Origin::Client);
+    }
+
+    #[test]
+    fn extended_header_roundtrip() {
+        let payload = vec![0u8; 8 * 100_000];
+        let message = Message::ReadNotifyResponse { data_type: 6, data_count: 100_000, status: 1, ioid: 4, payload };
+        let buf = message.as_bytes();
+        assert!(Header::is_extended(&buf));
+        assert_eq!(buf.len(), EXTENDED_HEADER_SIZE + 8 * 100_000);
+
+        let header = Header::from_bytes(&buf).unwrap();
+        assert_eq!(header.size(), EXTENDED_HEADER_SIZE);
+        assert_eq!(header.data_count(), 100_000);
+        assert!(MessageHeader::from_bytes(&buf[..HEADER_SIZE]).is_err());
+
+        roundtrip(message, Origin::Server);
+    }
+
+    #[test]
+    fn decode_multiple_messages() {
+        let mut buf = Message::Version { priority: 0, minor_version: 13 }.as_bytes();
+        buf.extend(Message::SearchResponse { port: 5064, server_ip: 0x7F000001, cid: 3, minor_version: 13 }.as_bytes());
+
+        let (first, size) = Message::from_bytes(&buf, Origin::Server).unwrap();
+        assert_eq!(first.command(), Command::CA_PROTO_VERSION);
+        let (second, _) = Message::from_bytes(&buf[size..], Origin::Server).unwrap();
+        assert_eq!(second.command(), Command::CA_PROTO_SEARCH);
+    }
+
+    #[test]
+    fn message_reader() {
+        let update = |size: usize| Message::EventAddResponse { data_type: 6, data_count: (size / 8) as u32, status: 1, subscription_id: 7, payload: vec![0u8; size] };
+        let mut data = Message::Echo.as_bytes();
+        data.extend(update(64).as_bytes());
+        data.extend(update(8).as_bytes());
+
+        // Feed the stream in small pieces; the oversized update is skipped even when split across reads
+        let mut reader = MessageReader::new(Origin::Server, 16);
+        let mut messages = vec!();
+        for chunk in data.chunks(5) {
+            messages.extend(reader.push(chunk).unwrap());
+        }
+        assert_eq!(messages, vec![Message::Echo, update(8)]);
+        assert!(reader.buf.is_empty());
+        assert_eq!(reader.discard, 0);
+    }
+}
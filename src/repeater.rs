@@ -1,31 +1,35 @@
-use std::{convert::TryFrom, net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket}, str::FromStr, sync::mpsc::Sender};
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket}, sync::{Mutex, mpsc::Sender}};
 
 use crate::protocol::{
     HEADER_SIZE,
-    MessageHeader,
-    Command,
+    Message,
+    Origin,
 };
 
-use log::{info, warn, error, debug, trace};
+use log::{info, warn, error, trace};
+
+/// Spawns a new repeater on `port` unless one is already running.
+pub fn init(port: u16) {
+    // Serialize initialization so that clients created concurrently in one process do not race to spawn repeaters
+    static INIT: Mutex<()> = Mutex::new(());
+    let _guard = INIT.lock().unwrap_or_else(|e| e.into_inner());
 
-/// Initializes a new repeater or connects to an existing repeater and returns a bound UDP socket for receiving messages.
-pub fn init() {
     // Check for existing repeater by attempting to bind to repeater port
-    if let Ok(socket) = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0,)), crate::CA_REPEATER_PORT)) {
+    if let Ok(socket) = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0,)), port)) {
         // No repeater bound. Close socket and spawn a new repeater.
         drop(socket);
-        spawn_repeater()
+        spawn_repeater(port)
     }
 }
 
 /// Spawns a new repeater on a seperate thread listening for incoming registrations and server beacons. Blocks until repeater is ready to receive messages.
-fn spawn_repeater() {
-    info!("Spawning new repeater on 0.0.0.0:{}", crate::CA_REPEATER_PORT);
+fn spawn_repeater(port: u16) {
+    info!("Spawning new repeater on 0.0.0.0:{}", port);
 
     let (tx, rx) = std::sync::mpsc::channel::<bool>();
 
     std::thread::spawn(move || {
-        let mut repeater = Repeater::new("0.0.0.0");
+        let mut repeater = Repeater::new("0.0.0.0", port);
 
         repeater.listen(tx);
     });
@@ -44,8 +48,8 @@ pub struct Repeater {
     registered_clients: Vec<RegisteredClient>,
 }
 impl Repeater {
-    pub fn new(bind_addr: &str) -> Self {
-        let socket = UdpSocket::bind(format!("{}:{}", bind_addr, crate::CA_REPEATER_PORT)).expect("Only a single repeater should be created per host");
+    pub fn new(bind_addr: &str, port: u16) -> Self {
+        let socket = UdpSocket::bind(format!("{}:{}", bind_addr, port)).expect("Only a single repeater should be created per host");
         Self {
             socket,
             registered_clients: vec!()
@@ -70,15 +74,18 @@ impl Repeater {
                 continue;
             }
 
-            // Parse packet into a MessageHeader
-            let header = MessageHeader::from_bytes(&buf).expect("Buffer should be 16-bytes");
-
             // Process received message
-            match Command::try_from(header.command) {
+            match Message::from_bytes(&buf, Origin::Server) {
                 // Forward server beacon to all registered clients
-                Ok(Command::CA_PROTO_RSRV_IS_UP) => {
+                Ok((Message::RsrvIsUp { minor_version, port, beacon_id, address }, _)) => {
+                    // Servers that leave the address blank are reachable at the beacon's source address
+                    let address = match (address, src.ip()) {
+                        (0, IpAddr::V4(ip)) => u32::from(ip),
+                        _ => address,
+                    };
+                    let beacon = Message::RsrvIsUp { minor_version, port, beacon_id, address }.as_bytes();
                     for client in &self.registered_clients {
-                        if let Err(e) = client.forward_socket.send_to(&buf, client.remote_address) {
+                        if let Err(e) = client.forward_socket.send_to(&beacon, client.remote_address) {
                             error!("Could not forward message to {:?}: {:?}", client.remote_address, e);
                             continue;
                         }
@@ -86,9 +93,9 @@ impl Repeater {
                 },
 
                 // Register client and send confirmation message
-                Ok(Command::CA_REPEATER_REGISTER) => {
+                Ok((Message::RepeaterRegister { address }, _)) => {
                     // Validate registration address matches source address
-                    let addr_buf: [u8;4] = header.parameter_2.to_be_bytes();
+                    let addr_buf: [u8;4] = address.to_be_bytes();
                     let received_addr = Ipv4Addr::new(addr_buf[0], addr_buf[1], addr_buf[2], addr_buf[3]);
                     let src_addr: IpAddr = src.ip();
 
@@ -123,13 +130,8 @@ impl Repeater {
                     };
 
                     // Create confirmation message
-                    let confirm_buf = MessageHeader {
-                        command: Command::CA_REPEATER_CONFIRM.into(),
-                        payload_size: 0,
-                        data_type: 0,
-                        data_count: 0,
-                        parameter_1: 0,
-                        parameter_2: u32::from_be_bytes(local_addr_buf),
+                    let confirm_buf = Message::RepeaterConfirm {
+                        address: u32::from_be_bytes(local_addr_buf),
                     };
 
                     // Send confirmation message
@@ -1,3 +1,191 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use mio::{Poll, Token, Waker};
+use crate::ServerConfig;
+
+use log::{info, warn};
+
+pub mod pv;
+pub mod database;
+mod beacon;
+mod circuit;
+mod event_loop;
+pub use pv::{ChangeNotifier, Metadata, ProcessVariable, WriteCompletion};
+pub use database::{AlarmSeverities, PvDatabase, SimplePv};
+use beacon::Beacons;
+use circuit::Circuit;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(String),
+    ProtocolError(String),
+    /// A request failed with an ECA status code and its message text.
+    EcaError(u32, String),
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(format!("{:?}",e))
+    }
+}
+impl From<crate::protocol::Error> for Error {
+    fn from(e: crate::protocol::Error) -> Self {
+        Error::ProtocolError(format!("{:?}",e))
+    }
+}
+impl Error {
+    /// Builds the error for a failed ECA status code.
+    pub fn from_eca(status: u32) -> Self {
+        Error::EcaError(status, crate::protocol::eca::message(status).into())
+    }
+
+    /// ECA status code reported to clients for the error, `fallback` for errors that do not carry one.
+    pub(crate) fn status(&self, fallback: u32) -> u32 {
+        match self {
+            Error::EcaError(status, _) => *status,
+            _ => fallback,
+        }
+    }
+}
+
+/// State shared between the server and its event loop.
+pub(crate) struct Shared {
+    config: ServerConfig,
+    pvs: Mutex<HashMap<String, Arc<dyn ProcessVariable>>>,
+    /// Open circuits by the token their sockets are registered under.
+    circuits: Mutex<HashMap<Token, Arc<Circuit>>>,
+    next_id: AtomicU32,
+    tcp_port: u16,
+    waker: Waker,
+    stopped: AtomicBool,
+}
+impl Shared {
+    /// Allocates an ID that is unique across channels and circuits of this server.
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn pv(&self, name: &str) -> Option<Arc<dyn ProcessVariable>> {
+        self.pvs.lock().unwrap().get(name).cloned()
+    }
+
+    fn open_circuits(&self) -> Vec<Arc<Circuit>> {
+        self.circuits.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Sends the current value of `pv` to every subscription to it whose event mask shares a bit with `mask`.
+    fn post(&self, pv: &Arc<dyn ProcessVariable>, mask: u16) {
+        for circuit in self.open_circuits() {
+            circuit.post(pv, mask);
+        }
+    }
+}
+
+/// Channel Access server hosting process variables.
+///
+/// Searches, circuits, requests and beacons are served by a single event loop thread. Dropping the server stops the loop
+/// at once and closes its circuits.
 pub struct Server {
+    shared: Arc<Shared>,
+    search_address: SocketAddr,
+    tcp_address: SocketAddr,
+}
+
+impl Server {
+    /// Creates a server configured from the EPICS_CAS_* and EPICS_CA_* environment variables.
+    pub fn new() -> Result<Self, Error> {
+        Self::with_config(ServerConfig::from_env())
+    }
+
+    /// Creates a server with an explicit configuration.
+    pub fn with_config(config: ServerConfig) -> Result<Self, Error> {
+        let search_socket = UdpSocket::bind((config.interface, config.server_port))?;
+        // Like rsrv, fall back to any free port for circuits when another server already uses the default one
+        let listener = TcpListener::bind((config.interface, config.server_port))
+            .or_else(|_| TcpListener::bind((config.interface, 0)))?;
+        let search_address = search_socket.local_addr()?;
+        let tcp_address = listener.local_addr()?;
+        let beacons = Beacons::new(&config, tcp_address.port())?;
+
+        let poll = Poll::new()?;
+        let shared = Arc::new(Shared {
+            config,
+            pvs: Mutex::new(HashMap::new()),
+            circuits: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            tcp_port: tcp_address.port(),
+            waker: Waker::new(poll.registry(), event_loop::WAKER)?,
+            stopped: AtomicBool::new(false),
+        });
+
+        event_loop::EventLoop::new(poll, shared.clone(), search_socket, listener, beacons)?.spawn();
+        info!("Serving searches on {} and circuits on {}", search_address, tcp_address);
+
+        Ok(Self { shared, search_address, tcp_address })
+    }
+
+    /// Returns the configuration the server was created with.
+    pub fn config(&self) -> &ServerConfig {
+        &self.shared.config
+    }
+
+    /// Address searches are received on.
+    pub fn search_address(&self) -> SocketAddr {
+        self.search_address
+    }
+
+    /// Address circuits are accepted on.
+    pub fn tcp_address(&self) -> SocketAddr {
+        self.tcp_address
+    }
+
+    /// Hosts `pv` under `name`, replacing any variable previously hosted under that name.
+    pub fn add_pv(&self, name: &str, pv: Arc<dyn ProcessVariable>) {
+        let previous = self.shared.pvs.lock().unwrap().insert(name.into(), pv.clone());
+        if let Some(previous) = previous {
+            self.disconnect(&previous);
+        }
+        pv.attach(ChangeNotifier::new(&self.shared, &pv));
+    }
+
+    /// Hosts every variable of `database` under its name.
+    pub fn add_database(&self, database: &PvDatabase) {
+        for (name, pv) in database.iter() {
+            self.add_pv(name, pv.clone());
+        }
+    }
+
+    /// Stops hosting `name`, disconnecting the channels connected to it. Returns false if it was not hosted.
+    pub fn remove_pv(&self, name: &str) -> bool {
+        let removed = self.shared.pvs.lock().unwrap().remove(name);
+        match removed {
+            Some(pv) => {
+                self.disconnect(&pv);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the names of the hosted process variables.
+    pub fn pv_names(&self) -> Vec<String> {
+        self.shared.pvs.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Sends CA_PROTO_SERVER_DISCONN for every channel connected to `pv`.
+    fn disconnect(&self, pv: &Arc<dyn ProcessVariable>) {
+        for circuit in self.shared.open_circuits() {
+            circuit.disconnect(pv);
+        }
+    }
+}
 
-}
\ No newline at end of file
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        if let Err(e) = self.shared.waker.wake() {
+            warn!("Could not wake event loop: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::ServerConfig;
+use crate::protocol::Message;
+use super::Error;
+
+use log::{debug, trace};
+
+/// Interval between the first two beacons, doubled after every beacon until the configured period is reached.
+const BEACON_START_PERIOD: f64 = 0.02;
+
+/// Announces the server with CA_PROTO_RSRV_IS_UP beacons, fast at first so that clients notice a restart at once,
+/// then backing off to the configured beacon period.
+pub(crate) struct Beacons {
+    socket: UdpSocket,
+    addresses: Vec<SocketAddr>,
+    /// Address announced in the beacons, 0 to let repeaters use the source address.
+    server_ip: u32,
+    tcp_port: u16,
+    beacon_id: u32,
+    period: Duration,
+    max_period: Duration,
+    next_send: Instant,
+}
+
+impl Beacons {
+    /// Prepares the beacons for a server accepting circuits on `tcp_port`. The first one is due at once.
+    pub(crate) fn new(config: &ServerConfig, tcp_port: u16) -> Result<Self, Error> {
+        let interface = match config.interface {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let socket = UdpSocket::bind((interface, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        // A zero period would send beacons on every turn of the event loop
+        let start_period = Duration::from_secs_f64(BEACON_START_PERIOD);
+        let max_period = config.beacon_period.max(start_period);
+        Ok(Self {
+            socket,
+            addresses: config.beacon_addresses(),
+            server_ip: u32::from(interface),
+            tcp_port,
+            beacon_id: 0,
+            period: start_period,
+            max_period,
+            next_send: Instant::now(),
+        })
+    }
+
+    /// Time the next beacon is due.
+    pub(crate) fn next_send(&self) -> Instant {
+        self.next_send
+    }
+
+    /// Sends the next beacon to every address if it is due at `now`.
+    pub(crate) fn send(&mut self, now: Instant) {
+        if now < self.next_send {
+            return;
+        }
+
+        let beacon = Message::RsrvIsUp {
+            minor_version: crate::MINOR_PROTOCOL_VERSION,
+            port: self.tcp_port,
+            beacon_id: self.beacon_id,
+            address: self.server_ip,
+        }.as_bytes();
+        for address in &self.addresses {
+            trace!("Sending beacon {} to {}", self.beacon_id, address);
+            if let Err(e) = self.socket.send_to(&beacon, address) {
+                debug!("Could not send beacon to {}: {:?}", address, e);
+            }
+        }
+
+        self.beacon_id = self.beacon_id.wrapping_add(1);
+        self.next_send = now + self.period;
+        self.period = (self.period * 2).min(self.max_period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn back_off() {
+        let config = ServerConfig::default()
+            .interface([127, 0, 0, 1].into())
+            .auto_beacon_addr_list(false)
+            .beacon_period(Duration::from_secs_f64(0.1));
+        let mut beacons = Beacons::new(&config, 5064).unwrap();
+
+        let start = Instant::now();
+        assert!(beacons.next_send() <= start);
+        let mut intervals = vec!();
+        let mut now = start;
+        for _ in 0..6 {
+            beacons.send(now);
+            intervals.push(beacons.next_send() - now);
+            now = beacons.next_send();
+        }
+        let expected: Vec<Duration> = [0.02, 0.04, 0.08, 0.1, 0.1, 0.1].iter().map(|secs| Duration::from_secs_f64(*secs)).collect();
+        assert_eq!(intervals, expected);
+        assert_eq!(beacons.beacon_id, 6);
+
+        // Nothing is sent before the next beacon is due
+        beacons.send(now - Duration::from_millis(1));
+        assert_eq!(beacons.beacon_id, 6);
+
+        // Periods too short to back off to are raised to the starting period
+        let mut beacons = Beacons::new(&config.beacon_period(Duration::ZERO), 5064).unwrap();
+        let now = Instant::now();
+        beacons.send(now);
+        beacons.send(now);
+        assert_eq!(beacons.beacon_id, 1);
+        assert_eq!(beacons.next_send() - now, Duration::from_secs_f64(BEACON_START_PERIOD));
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mio::{Interest, Registry, Token};
+use mio::event::Event;
+use mio::net::TcpStream;
+
+use crate::dbr::{Category, Dbr, FieldType, Graphic, Sts, Time, Value};
+use crate::ServerConfig;
+use crate::protocol::{eca, AccessRights, DataType, Message, MessageReader, Origin};
+use super::{Error, ProcessVariable, Shared, WriteCompletion};
+
+use log::{info, warn, debug, trace};
+
+/// Socket of a circuit and the bytes still waiting to be written to it.
+struct Io {
+    stream: TcpStream,
+    outgoing: Vec<u8>,
+    /// The socket is registered for writability because `outgoing` could not be flushed.
+    writable_interest: bool,
+}
+
+/// Parameters of a CA_PROTO_EVENT_ADD request and the encoded updates waiting to be sent for it.
+struct Subscription {
+    data_type: DataType,
+    count: u32,
+    mask: u16,
+    queue: VecDeque<Vec<u8>>,
+}
+
+/// Channel created by the client, keyed by its SID.
+struct Channel {
+    cid: u32,
+    name: String,
+    pv: Arc<dyn ProcessVariable>,
+    subscriptions: HashMap<u32, Subscription>,
+}
+
+/// TCP virtual circuit accepted from one client.
+///
+/// Requests are served on the server's event loop thread. Sends are buffered and never wait for the client.
+/// Subscription updates are only handed to the socket once it has taken everything else, and wait in bounded
+/// per-subscription queues until then.
+pub(crate) struct Circuit {
+    peer: SocketAddr,
+    token: Token,
+    registry: Registry,
+    io: Mutex<Io>,
+    reader: Mutex<MessageReader>,
+    channels: Mutex<HashMap<u32, Channel>>,
+    max_array_bytes: usize,
+    event_queue_size: usize,
+    /// Cleared while the client has turned subscription updates off with CA_PROTO_EVENTS_OFF.
+    events_on: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl Circuit {
+    /// Registers an accepted connection with the event loop's `registry` under `token`.
+    pub(crate) fn new(mut stream: TcpStream, peer: SocketAddr, registry: &Registry, token: Token, config: &ServerConfig) -> Result<Arc<Self>, Error> {
+        registry.register(&mut stream, token, Interest::READABLE)?;
+        if let Err(e) = stream.set_nodelay(true) {
+            debug!("Could not disable Nagle's algorithm on circuit from {}: {:?}", peer, e);
+        }
+        info!("Accepted virtual circuit from {}", peer);
+
+        Ok(Arc::new(Self {
+            peer,
+            token,
+            registry: registry.try_clone()?,
+            io: Mutex::new(Io { stream, outgoing: vec!(), writable_interest: false }),
+            reader: Mutex::new(MessageReader::new(Origin::Client, config.max_array_bytes)),
+            channels: Mutex::new(HashMap::new()),
+            max_array_bytes: config.max_array_bytes,
+            event_queue_size: config.event_queue_size.max(1),
+            events_on: AtomicBool::new(true),
+            closed: AtomicBool::new(false),
+        }))
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Encodes and sends a message to the client, buffering whatever the socket cannot take yet.
+    fn send(&self, message: &Message) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut io = self.io.lock().unwrap();
+        let backlogged = io.writable_interest;
+        io.outgoing.extend(message.as_bytes());
+        if let Err(e) = self.flush(&mut io) {
+            debug!("Could not send to {}: {:?}", self.peer, e);
+        }
+        let drained = backlogged && !io.writable_interest;
+        drop(io);
+
+        if drained {
+            self.send_events();
+        }
+    }
+
+    /// Writes as much of the outgoing buffer as the socket accepts, waiting for writability while data is left over.
+    fn flush(&self, io: &mut Io) -> Result<(), Error> {
+        while !io.outgoing.is_empty() {
+            match io.stream.write(&io.outgoing) {
+                Ok(0) => return Err(Error::IoError(format!("Virtual circuit from {} stopped accepting data", self.peer))),
+                Ok(amt) => { io.outgoing.drain(..amt); },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let writable_interest = !io.outgoing.is_empty();
+        if writable_interest != io.writable_interest {
+            let interest = if writable_interest { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+            self.registry.reregister(&mut io.stream, self.token, interest)?;
+            io.writable_interest = writable_interest;
+        }
+        Ok(())
+    }
+
+    /// Handles a readiness event from the event loop, returning false once the connection has ended.
+    pub(crate) fn ready(self: &Arc<Self>, event: &Event, shared: &Shared) -> bool {
+        if event.is_writable() {
+            if let Err(e) = self.flush(&mut self.io.lock().unwrap()) {
+                warn!("Virtual circuit from {} lost: {:?}", self.peer, e);
+                return false
+            }
+            self.send_events();
+        }
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            return self.readable(shared)
+        }
+        true
+    }
+
+    /// Reads everything available on the socket and serves the complete requests.
+    fn readable(self: &Arc<Self>, shared: &Shared) -> bool {
+        let mut buf = [0u8; 16384];
+        let mut messages = vec!();
+        let alive = loop {
+            let result = self.io.lock().unwrap().stream.read(&mut buf);
+            match result {
+                Ok(0) => break false,
+                Ok(amt) => match self.reader.lock().unwrap().push(&buf[..amt]) {
+                    Ok(received) => messages.extend(received),
+                    Err(e) => {
+                        warn!("Virtual circuit from {} lost: {:?}", self.peer, e);
+                        break false
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if !self.closed.load(Ordering::SeqCst) {
+                        warn!("Virtual circuit from {} lost: {:?}", self.peer, e);
+                    }
+                    break false
+                },
+            }
+        };
+
+        for message in messages {
+            self.handle(message, shared);
+        }
+        alive
+    }
+
+    /// Shuts the connection down.
+    pub(crate) fn close(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            if let Err(e) = self.io.lock().unwrap().stream.shutdown(Shutdown::Both) {
+                trace!("Could not shut down circuit from {}: {:?}", self.peer, e);
+            }
+        }
+    }
+
+    /// Deregisters the ended connection and forgets its channels.
+    pub(crate) fn finish(&self) {
+        self.close();
+        info!("Virtual circuit from {} closed", self.peer);
+        if let Err(e) = self.registry.deregister(&mut self.io.lock().unwrap().stream) {
+            trace!("Could not deregister circuit from {}: {:?}", self.peer, e);
+        }
+        self.channels.lock().unwrap().clear();
+    }
+
+    /// Serves one request from the client.
+    fn handle(self: &Arc<Self>, message: Message, shared: &Shared) {
+        trace!("Received {:?} from {}", message.command(), self.peer);
+        match message {
+            Message::Version { minor_version, .. } => {
+                debug!("Client {} speaks CA minor version {}", self.peer, minor_version);
+                self.send(&Message::Version { priority: 0, minor_version: crate::MINOR_PROTOCOL_VERSION });
+            },
+            Message::ClientName { name } => debug!("Client {} is user {}", self.peer, name),
+            Message::HostName { name } => debug!("Client {} is on host {}", self.peer, name),
+            Message::CreateChan { cid, name, .. } => self.create_channel(cid, name, shared),
+            Message::ClearChannel { sid, cid } => {
+                self.channels.lock().unwrap().remove(&sid);
+                self.send(&Message::ClearChannel { sid, cid });
+            },
+            Message::ReadNotify { data_type, data_count, sid, ioid } => {
+                let response = match self.read(sid, data_type, data_count) {
+                    Ok(dbr) => Message::ReadNotifyResponse { data_type, data_count: dbr.count() as u32, status: eca::ECA_NORMAL, ioid, payload: dbr.as_bytes() },
+                    Err(e) => Message::ReadNotifyResponse { data_type, data_count: 0, status: e.status(eca::ECA_GETFAIL), ioid, payload: vec!() },
+                };
+                self.send(&response);
+            },
+            Message::Write { data_type, data_count, sid, payload, .. } => {
+                let peer = self.peer;
+                self.write(sid, data_type, data_count, &payload, WriteCompletion::new(move |result| {
+                    if let Err(e) = result {
+                        debug!("Write from {} failed: {:?}", peer, e);
+                    }
+                }));
+            },
+            Message::WriteNotify { data_type, data_count, sid, ioid, payload } => {
+                // The write may complete on another thread, after the circuit is gone
+                let circuit = Arc::downgrade(self);
+                self.write(sid, data_type, data_count, &payload, WriteCompletion::new(move |result| {
+                    let status = match result {
+                        Ok(()) => eca::ECA_NORMAL,
+                        Err(e) => e.status(eca::ECA_PUTFAIL),
+                    };
+                    if let Some(circuit) = circuit.upgrade() {
+                        circuit.send(&Message::WriteNotifyResponse { data_type, data_count, status, ioid });
+                    }
+                }));
+            },
+            Message::EventAdd { data_type, data_count, sid, subscription_id, mask } => {
+                self.subscribe(sid, data_type, data_count, subscription_id, mask);
+            },
+            Message::EventCancel { data_type, data_count, sid, subscription_id } => {
+                if let Some(channel) = self.channels.lock().unwrap().get_mut(&sid) {
+                    channel.subscriptions.remove(&subscription_id);
+                }
+                self.send(&Message::EventAddResponse { data_type, data_count, status: eca::ECA_NORMAL, subscription_id, payload: vec!() });
+            },
+            Message::EventsOff => {
+                debug!("Client {} turned events off", self.peer);
+                self.events_on.store(false, Ordering::SeqCst);
+            },
+            Message::EventsOn => {
+                debug!("Client {} turned events on", self.peer);
+                self.events_on.store(true, Ordering::SeqCst);
+                self.send_events();
+            },
+            Message::Echo => self.send(&Message::Echo),
+            message => debug!("Ignoring {:?} from {}", message.command(), self.peer),
+        }
+    }
+
+    /// Creates a channel to the hosted variable `name`, or refuses it if there is none.
+    fn create_channel(&self, cid: u32, name: String, shared: &Shared) {
+        let pv = match shared.pv(&name) {
+            Some(pv) => pv,
+            None => {
+                debug!("Client {} asked for unknown channel {}", self.peer, name);
+                self.send(&Message::CreateChFail { cid });
+                return;
+            }
+        };
+
+        let sid = shared.next_id();
+        let native_type = DataType::from_parts(Category::Plain, pv.native_type());
+        let element_count = pv.element_count() as u32;
+        debug!("Client {} connected to {} (cid {}, sid {})", self.peer, name, cid, sid);
+        self.channels.lock().unwrap().insert(sid, Channel { cid, name, pv, subscriptions: HashMap::new() });
+
+        self.send(&Message::AccessRights { cid, access_rights: AccessRights { read: true, write: true }.bits() });
+        self.send(&Message::CreateChanResponse { data_type: native_type.into(), data_count: element_count, cid, sid });
+    }
+
+    /// Returns the variable behind channel `sid`.
+    fn pv(&self, sid: u32) -> Result<Arc<dyn ProcessVariable>, Error> {
+        self.channels.lock().unwrap().get(&sid)
+            .map(|channel| channel.pv.clone())
+            .ok_or_else(|| Error::from_eca(eca::ECA_BADCHID))
+    }
+
+    /// Reads channel `sid` as `data_count` elements of `data_type`.
+    fn read(&self, sid: u32, data_type: u16, data_count: u32) -> Result<Dbr, Error> {
+        let pv = self.pv(sid)?;
+        let data_type = DataType::try_from(data_type).map_err(|_| Error::from_eca(eca::ECA_BADTYPE))?;
+        read(pv.as_ref(), data_type, data_count, self.max_array_bytes)
+    }
+
+    /// Starts writing a value to channel `sid`, completing `completion` at once if the request is invalid.
+    fn write(&self, sid: u32, data_type: u16, data_count: u32, payload: &[u8], completion: WriteCompletion) {
+        match self.decode_write(sid, data_type, data_count, payload) {
+            Ok((pv, value)) => pv.write(value, completion),
+            Err(e) => completion.complete(Err(e)),
+        }
+    }
+
+    /// Returns the variable behind channel `sid` and the written value converted to its native type.
+    fn decode_write(&self, sid: u32, data_type: u16, data_count: u32, payload: &[u8]) -> Result<(Arc<dyn ProcessVariable>, Value), Error> {
+        let pv = self.pv(sid)?;
+        let data_type = DataType::try_from(data_type).map_err(|_| Error::from_eca(eca::ECA_BADTYPE))?;
+        if data_type.category() != Category::Plain {
+            return Err(Error::from_eca(eca::ECA_BADTYPE))
+        }
+        if data_count == 0 || data_count as usize > pv.element_count() {
+            return Err(Error::from_eca(eca::ECA_BADCOUNT))
+        }
+        let value = Value::from_bytes(data_type.field_type(), data_count as usize, payload)?;
+        let value = convert(value, pv.native_type(), &pv.metadata().enum_strings)?;
+        Ok((pv, value))
+    }
+
+    /// Registers a subscription to channel `sid` and queues its initial update.
+    fn subscribe(&self, sid: u32, data_type: u16, data_count: u32, subscription_id: u32, mask: u16) {
+        let subscription = DataType::try_from(data_type)
+            .map_err(|_| Error::from_eca(eca::ECA_BADTYPE))
+            .and_then(|data_type| {
+                let mut channels = self.channels.lock().unwrap();
+                let channel = channels.get_mut(&sid).ok_or_else(|| Error::from_eca(eca::ECA_BADCHID))?;
+                channel.subscriptions.insert(subscription_id, Subscription { data_type, count: data_count, mask, queue: VecDeque::new() });
+                Ok(channel.pv.clone())
+            });
+
+        match subscription {
+            Ok(pv) => self.queue_update(pv.as_ref(), sid, subscription_id, data_type, data_count),
+            Err(e) => self.send(&Message::EventAddResponse {
+                data_type,
+                data_count: 0,
+                status: e.status(eca::ECA_INTERNAL),
+                subscription_id,
+                payload: vec!(),
+            }),
+        }
+    }
+
+    /// Sends the current value of `pv` to every subscription to it whose event mask shares a bit with `mask`.
+    pub(crate) fn post(&self, pv: &Arc<dyn ProcessVariable>, mask: u16) {
+        let updates: Vec<(u32, u32, u16, u32)> = self.channels.lock().unwrap().iter()
+            .filter(|(_, channel)| Arc::ptr_eq(&channel.pv, pv))
+            .flat_map(|(sid, channel)| channel.subscriptions.iter().map(move |(id, subscription)| (*sid, *id, subscription)))
+            .filter(|(_, _, subscription)| subscription.mask & mask != 0)
+            .map(|(sid, id, subscription)| (sid, id, subscription.data_type.into(), subscription.count))
+            .collect();
+
+        // Read unlocked so the variable can call back into the server
+        for (sid, subscription_id, data_type, data_count) in updates {
+            self.queue_update(pv.as_ref(), sid, subscription_id, data_type, data_count);
+        }
+    }
+
+    /// Queues a CA_PROTO_EVENT_ADD response carrying the current value of `pv` for a subscription to channel `sid`,
+    /// then sends whatever updates the client can take.
+    fn queue_update(&self, pv: &dyn ProcessVariable, sid: u32, subscription_id: u32, data_type: u16, data_count: u32) {
+        let dbr = DataType::try_from(data_type)
+            .map_err(|_| Error::from_eca(eca::ECA_BADTYPE))
+            .and_then(|dbr_type| read(pv, dbr_type, data_count, self.max_array_bytes));
+        let update = match dbr {
+            Ok(dbr) => Message::EventAddResponse { data_type, data_count: dbr.count() as u32, status: eca::ECA_NORMAL, subscription_id, payload: dbr.as_bytes() },
+            Err(e) => Message::EventAddResponse { data_type, data_count: 0, status: e.status(eca::ECA_GETFAIL), subscription_id, payload: vec!() },
+        }.as_bytes();
+
+        {
+            let mut channels = self.channels.lock().unwrap();
+            // The subscription may have been cancelled while the variable was read
+            let subscription = match channels.get_mut(&sid).and_then(|channel| channel.subscriptions.get_mut(&subscription_id)) {
+                Some(subscription) => subscription,
+                None => return,
+            };
+            if subscription.queue.len() < self.event_queue_size {
+                subscription.queue.push_back(update);
+            } else {
+                // Coalesce into the newest queued update so that the latest value still gets through
+                trace!("Event queue of subscription {} from {} is full", subscription_id, self.peer);
+                *subscription.queue.back_mut().unwrap() = update;
+            }
+        }
+        self.send_events();
+    }
+
+    /// Hands queued updates to the socket, one per subscription in turn, as long as events are on
+    /// and the socket has taken everything sent before.
+    fn send_events(&self) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut channels = self.channels.lock().unwrap();
+        let mut io = self.io.lock().unwrap();
+        while self.events_on.load(Ordering::SeqCst) && io.outgoing.is_empty() {
+            let mut queued = false;
+            for subscription in channels.values_mut().flat_map(|channel| channel.subscriptions.values_mut()) {
+                if let Some(update) = subscription.queue.pop_front() {
+                    io.outgoing.extend(update);
+                    queued = true;
+                }
+            }
+            if !queued {
+                break;
+            }
+            if let Err(e) = self.flush(&mut io) {
+                debug!("Could not send events to {}: {:?}", self.peer, e);
+                break;
+            }
+        }
+    }
+
+    /// Sends CA_PROTO_SERVER_DISCONN for every channel connected to `pv` and forgets them.
+    pub(crate) fn disconnect(&self, pv: &Arc<dyn ProcessVariable>) {
+        let mut disconnected = vec!();
+        self.channels.lock().unwrap().retain(|_, channel| {
+            let connected = Arc::ptr_eq(&channel.pv, pv);
+            if connected {
+                disconnected.push((channel.cid, channel.name.clone()));
+            }
+            !connected
+        });
+        for (cid, name) in disconnected {
+            debug!("Disconnecting client {} from {}", self.peer, name);
+            self.send(&Message::ServerDisconn { cid });
+        }
+    }
+}
+
+/// Reads `pv` as `count` elements of `data_type`, a count of zero meaning every element.
+fn read(pv: &dyn ProcessVariable, data_type: DataType, count: u32, max_array_bytes: usize) -> Result<Dbr, Error> {
+    let element_count = pv.element_count();
+    let count = if count == 0 { element_count } else { count as usize };
+    if count > element_count {
+        return Err(Error::from_eca(eca::ECA_BADCOUNT))
+    }
+    if data_type.payload_size(count) > max_array_bytes {
+        return Err(Error::from_eca(eca::ECA_TOLARGE))
+    }
+
+    let Time { alarm, stamp, value } = pv.read()?;
+    let metadata = pv.metadata();
+    let mut value = convert(value, data_type.field_type(), &metadata.enum_strings)?;
+    value.resize(count);
+
+    let graphic = |value| Graphic {
+        alarm,
+        units: metadata.units,
+        precision: metadata.precision,
+        limits: metadata.limits,
+        enum_strings: metadata.enum_strings,
+        value,
+    };
+    Ok(match data_type.category() {
+        Category::Plain => Dbr::Plain(value),
+        Category::Sts => Dbr::Sts(Sts { alarm, value }),
+        Category::Time => Dbr::Time(Time { alarm, stamp, value }),
+        Category::Gr => Dbr::Gr(graphic(value)),
+        Category::Ctrl => Dbr::Ctrl(graphic(value)),
+        _ => return Err(Error::from_eca(eca::ECA_BADTYPE)),
+    })
+}
+
+/// Converts `value` to `field_type`, naming enum states after `enum_strings` when converting to or from strings.
+fn convert(value: Value, field_type: FieldType, enum_strings: &[String]) -> Result<Value, Error> {
+    Ok(match (value, field_type) {
+        (Value::Enum(states), FieldType::String) if !enum_strings.is_empty() => Value::String(states.into_iter()
+            .map(|state| enum_strings.get(state as usize).cloned().unwrap_or_else(|| state.to_string()))
+            .collect()),
+        (Value::String(names), FieldType::Enum) if !enum_strings.is_empty() => Value::Enum(names.iter()
+            .map(|name| enum_strings.iter().position(|s| s == name.trim()).map(|state| state as u16)
+                .or_else(|| name.trim().parse().ok())
+                .ok_or_else(|| Error::from_eca(eca::ECA_NOCONVERT)))
+            .collect::<Result<_, _>>()?),
+        (value, field_type) => value.convert(field_type).map_err(|_| Error::from_eca(eca::ECA_NOCONVERT))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enum_strings() {
+        let strings = vec!["Off".to_string(), "On".to_string()];
+        let names = convert(Value::Enum(vec![1, 0, 2]), FieldType::String, &strings).unwrap();
+        assert_eq!(names, Value::String(vec!["On".into(), "Off".into(), "2".into()]));
+        let states = convert(Value::String(vec!["On".into(), " Off ".into(), "1".into()]), FieldType::Enum, &strings).unwrap();
+        assert_eq!(states, Value::Enum(vec![1, 0, 1]));
+        assert!(matches!(convert(Value::String(vec!["Standby".into()]), FieldType::Enum, &strings), Err(Error::EcaError(eca::ECA_NOCONVERT, _))));
+        assert_eq!(convert(Value::Enum(vec![1]), FieldType::String, &[]).unwrap(), Value::String(vec!["1".into()]));
+    }
+}
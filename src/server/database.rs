@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::dbr::{alarm, Alarm, EpicsTime, FieldType, IntoValue, Limits, Time, Value};
+use crate::protocol::{eca, DBE_ALARM, DBE_LOG, DBE_VALUE};
+use super::{ChangeNotifier, Error, Metadata, ProcessVariable, WriteCompletion};
+
+/// Severities raised when the value reaches each alarm limit, like the HHSV, HSV, LSV and LLSV record fields.
+/// A limit whose severity is NO_ALARM is not checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlarmSeverities {
+    pub hihi: u16,
+    pub high: u16,
+    pub low: u16,
+    pub lolo: u16,
+}
+
+struct State {
+    value: Value,
+    alarm: Alarm,
+    stamp: EpicsTime,
+    metadata: Metadata,
+    severities: AlarmSeverities,
+    /// MDEL: change from `monitored` needed to post DBE_VALUE.
+    value_deadband: f64,
+    /// ADEL: change from `archived` needed to post DBE_LOG.
+    archive_deadband: f64,
+    /// MLST: value last posted with DBE_VALUE.
+    monitored: Value,
+    /// ALST: value last posted with DBE_LOG.
+    archived: Value,
+}
+
+impl State {
+    /// Alarm state of the first element against the alarm limits, checked from the most severe down.
+    fn check_alarm(&self) -> Alarm {
+        let value = match first_number(&self.value) {
+            Some(value) => value,
+            None => return Alarm::default(),
+        };
+        let limits = &self.metadata.limits;
+        let severities = &self.severities;
+        let checks = [
+            (severities.hihi, alarm::status::HIHI_ALARM, value >= limits.upper_alarm_limit),
+            (severities.lolo, alarm::status::LOLO_ALARM, value <= limits.lower_alarm_limit),
+            (severities.high, alarm::status::HIGH_ALARM, value >= limits.upper_warning_limit),
+            (severities.low, alarm::status::LOW_ALARM, value <= limits.lower_warning_limit),
+        ];
+        checks.iter()
+            .find(|(severity, _, reached)| *severity != alarm::severity::NO_ALARM && *reached)
+            .map_or(Alarm::default(), |(severity, status, _)| Alarm { status: *status, severity: *severity })
+    }
+}
+
+/// Process variable holding its value in memory, with the display, control and alarm properties of a record.
+///
+/// Values written by clients are clamped to the control limits when those are set. The alarm state is recomputed
+/// from the alarm limits whenever the value changes. Changes are posted to DBE_VALUE and DBE_LOG subscribers once they
+/// exceed the value and archive deadbands, and to DBE_ALARM subscribers when the alarm state changes.
+pub struct SimplePv {
+    state: Mutex<State>,
+    notifiers: Mutex<Vec<ChangeNotifier>>,
+}
+
+impl SimplePv {
+    /// Creates a variable holding `value`, whose type and element count it keeps from then on.
+    pub fn new<T: IntoValue>(value: T) -> Self {
+        let value = value.into_value();
+        Self {
+            state: Mutex::new(State {
+                value: value.clone(),
+                alarm: Alarm::default(),
+                stamp: EpicsTime::now(),
+                metadata: Metadata::default(),
+                severities: AlarmSeverities::default(),
+                value_deadband: 0.0,
+                archive_deadband: 0.0,
+                monitored: value.clone(),
+                archived: value,
+            }),
+            notifiers: Mutex::new(vec!()),
+        }
+    }
+
+    pub fn units(self, units: &str) -> Self {
+        self.configure(|state| state.metadata.units = units.into())
+    }
+
+    pub fn precision(self, precision: i16) -> Self {
+        self.configure(|state| state.metadata.precision = precision)
+    }
+
+    /// Sets the LOPR and HOPR display limits.
+    pub fn display_limits(self, lower: f64, upper: f64) -> Self {
+        self.configure(|state| {
+            state.metadata.limits.lower_disp_limit = lower;
+            state.metadata.limits.upper_disp_limit = upper;
+        })
+    }
+
+    /// Sets the DRVL and DRVH control limits, which written values are clamped to unless `lower >= upper`.
+    pub fn control_limits(self, lower: f64, upper: f64) -> Self {
+        self.configure(|state| {
+            state.metadata.limits.lower_ctrl_limit = lower;
+            state.metadata.limits.upper_ctrl_limit = upper;
+        })
+    }
+
+    /// Sets the LOLO, LOW, HIGH and HIHI alarm limits, raising MAJOR alarms at LOLO and HIHI and MINOR ones
+    /// at LOW and HIGH unless other severities are set.
+    pub fn alarm_limits(self, lolo: f64, low: f64, high: f64, hihi: f64) -> Self {
+        self.configure(|state| {
+            let limits = &mut state.metadata.limits;
+            limits.lower_alarm_limit = lolo;
+            limits.lower_warning_limit = low;
+            limits.upper_warning_limit = high;
+            limits.upper_alarm_limit = hihi;
+            if state.severities == AlarmSeverities::default() {
+                state.severities = AlarmSeverities {
+                    hihi: alarm::severity::MAJOR_ALARM,
+                    high: alarm::severity::MINOR_ALARM,
+                    low: alarm::severity::MINOR_ALARM,
+                    lolo: alarm::severity::MAJOR_ALARM,
+                };
+            }
+        })
+    }
+
+    pub fn alarm_severities(self, severities: AlarmSeverities) -> Self {
+        self.configure(|state| state.severities = severities)
+    }
+
+    /// Sets the MDEL deadband: DBE_VALUE is only posted once a scalar moves further than this from the value last
+    /// posted. Values that are not numeric scalars are posted on any change, and a negative deadband posts every update.
+    pub fn value_deadband(self, deadband: f64) -> Self {
+        self.configure(|state| state.value_deadband = deadband)
+    }
+
+    /// Sets the ADEL deadband, which does for DBE_LOG what [`value_deadband`](Self::value_deadband) does for DBE_VALUE.
+    pub fn archive_deadband(self, deadband: f64) -> Self {
+        self.configure(|state| state.archive_deadband = deadband)
+    }
+
+    /// Names the states of an enum value, in order.
+    pub fn enum_strings(self, enum_strings: &[&str]) -> Self {
+        self.configure(|state| state.metadata.enum_strings = enum_strings.iter().map(|s| s.to_string()).collect())
+    }
+
+    /// Applies a builder change and recomputes the alarm state it may affect.
+    fn configure<F: FnOnce(&mut State)>(mut self, change: F) -> Self {
+        let state = self.state.get_mut().unwrap();
+        change(state);
+        state.alarm = state.check_alarm();
+        self
+    }
+
+    /// Returns the current value.
+    pub fn value(&self) -> Value {
+        self.state.lock().unwrap().value.clone()
+    }
+
+    /// Returns the current alarm state.
+    pub fn alarm(&self) -> Alarm {
+        self.state.lock().unwrap().alarm
+    }
+
+    /// Updates the value, e.g. with a new readback, without applying the control limits.
+    /// The value is converted to the variable's type and padded or truncated to its element count.
+    pub fn set<T: IntoValue>(&self, value: T) -> Result<(), Error> {
+        let value = value.into_value()
+            .convert(self.native_type())
+            .map_err(|_| Error::from_eca(eca::ECA_NOCONVERT))?;
+        self.store(value);
+        Ok(())
+    }
+
+    /// Stores a value of the native type, then posts the changes that pass the deadbands.
+    fn store(&self, mut value: Value) {
+        let mask = {
+            let mut state = self.state.lock().unwrap();
+            value.resize(state.value.len());
+            let mut mask = 0;
+            if exceeds_deadband(&state.monitored, &value, state.value_deadband) {
+                state.monitored = value.clone();
+                mask |= DBE_VALUE;
+            }
+            if exceeds_deadband(&state.archived, &value, state.archive_deadband) {
+                state.archived = value.clone();
+                mask |= DBE_LOG;
+            }
+            state.value = value;
+            state.stamp = EpicsTime::now();
+            let alarm = state.check_alarm();
+            if alarm != state.alarm {
+                state.alarm = alarm;
+                mask |= DBE_ALARM;
+            }
+            mask
+        };
+        if mask == 0 {
+            return;
+        }
+
+        // Post unlocked, as posting reads the variable
+        let notifiers = self.notifiers.lock().unwrap().clone();
+        for notifier in notifiers {
+            notifier.post(mask);
+        }
+    }
+}
+
+impl ProcessVariable for SimplePv {
+    fn native_type(&self) -> FieldType {
+        self.state.lock().unwrap().value.field_type()
+    }
+
+    fn element_count(&self) -> usize {
+        self.state.lock().unwrap().value.len()
+    }
+
+    fn read(&self) -> Result<Time, Error> {
+        let state = self.state.lock().unwrap();
+        Ok(Time { alarm: state.alarm, stamp: state.stamp, value: state.value.clone() })
+    }
+
+    fn write(&self, value: Value, completion: WriteCompletion) {
+        let limits = self.state.lock().unwrap().metadata.limits;
+        self.store(clamp(value, &limits));
+        completion.complete(Ok(()));
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.state.lock().unwrap().metadata.clone()
+    }
+
+    fn attach(&self, notifier: ChangeNotifier) {
+        let mut notifiers = self.notifiers.lock().unwrap();
+        notifiers.retain(ChangeNotifier::is_alive);
+        notifiers.push(notifier);
+    }
+}
+
+/// Named collection of [`SimplePv`]s, hosted together with [`Server::add_database`](super::Server::add_database).
+#[derive(Default)]
+pub struct PvDatabase {
+    pvs: HashMap<String, Arc<SimplePv>>,
+}
+
+impl PvDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `pv` under `name`, returning the handle to update it through.
+    pub fn add(&mut self, name: &str, pv: SimplePv) -> Arc<SimplePv> {
+        let pv = Arc::new(pv);
+        self.pvs.insert(name.into(), pv.clone());
+        pv
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<SimplePv>> {
+        self.pvs.get(name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<SimplePv>)> {
+        self.pvs.iter().map(|(name, pv)| (name.as_str(), pv))
+    }
+}
+
+/// First element of a numeric value, which is what alarm limits are checked against.
+fn first_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Short(v) => v.first().map(|&x| x as f64),
+        Value::Float(v) => v.first().map(|&x| x as f64),
+        Value::Char(v) => v.first().map(|&x| x as f64),
+        Value::Long(v) => v.first().map(|&x| x as f64),
+        Value::Double(v) => v.first().copied(),
+        Value::String(_) | Value::Enum(_) => None,
+    }
+}
+
+/// Whether `value` differs enough from the last posted one to be posted again.
+fn exceeds_deadband(last: &Value, value: &Value, deadband: f64) -> bool {
+    if deadband < 0.0 {
+        return true
+    }
+    match (first_number(last), first_number(value)) {
+        (Some(previous), Some(current)) if value.len() == 1 => {
+            if previous.is_nan() || current.is_nan() {
+                previous.is_nan() != current.is_nan()
+            } else {
+                (current - previous).abs() > deadband
+            }
+        },
+        _ => last != value,
+    }
+}
+
+/// Clamps every element of a numeric value to the control limits, if they are set.
+fn clamp(value: Value, limits: &Limits) -> Value {
+    let (lower, upper) = (limits.lower_ctrl_limit, limits.upper_ctrl_limit);
+    if lower >= upper {
+        return value
+    }
+    match value {
+        Value::Short(v) => Value::Short(v.into_iter().map(|x| (x as f64).clamp(lower, upper) as i16).collect()),
+        Value::Float(v) => Value::Float(v.into_iter().map(|x| (x as f64).clamp(lower, upper) as f32).collect()),
+        Value::Char(v) => Value::Char(v.into_iter().map(|x| (x as f64).clamp(lower, upper) as u8).collect()),
+        Value::Long(v) => Value::Long(v.into_iter().map(|x| (x as f64).clamp(lower, upper) as i32).collect()),
+        Value::Double(v) => Value::Double(v.into_iter().map(|x| x.clamp(lower, upper)).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alarm_limits() {
+        let pv = SimplePv::new(5.0).alarm_limits(0.0, 2.0, 8.0, 10.0).control_limits(-5.0, 15.0);
+        assert_eq!(pv.alarm(), Alarm::default());
+
+        let checks = [
+            (8.0, alarm::status::HIGH_ALARM, alarm::severity::MINOR_ALARM),
+            (12.0, alarm::status::HIHI_ALARM, alarm::severity::MAJOR_ALARM),
+            (1.0, alarm::status::LOW_ALARM, alarm::severity::MINOR_ALARM),
+            (-1.0, alarm::status::LOLO_ALARM, alarm::severity::MAJOR_ALARM),
+            (5.0, alarm::status::NO_ALARM, alarm::severity::NO_ALARM),
+        ];
+        for (value, status, severity) in checks {
+            pv.set(value).unwrap();
+            assert_eq!(pv.alarm(), Alarm { status, severity }, "at {}", value);
+        }
+
+        // Client writes are clamped, local updates are not
+        pv.write(Value::Double(vec![20.0]), WriteCompletion::new(|result| assert!(result.is_ok())));
+        assert_eq!(pv.value(), Value::Double(vec![15.0]));
+        pv.set(20i32).unwrap();
+        assert_eq!(pv.value(), Value::Double(vec![20.0]));
+
+        let pv = SimplePv::new(5.0).alarm_limits(0.0, 2.0, 8.0, 10.0)
+            .alarm_severities(AlarmSeverities { hihi: alarm::severity::INVALID_ALARM, ..AlarmSeverities::default() });
+        pv.set(9.0).unwrap();
+        assert_eq!(pv.alarm(), Alarm::default());
+        pv.set(10.0).unwrap();
+        assert_eq!(pv.alarm(), Alarm { status: alarm::status::HIHI_ALARM, severity: alarm::severity::INVALID_ALARM });
+    }
+
+    #[test]
+    fn deadbands() {
+        let last = Value::Double(vec![1.0]);
+        assert!(!exceeds_deadband(&last, &Value::Double(vec![1.0]), 0.0));
+        assert!(exceeds_deadband(&last, &Value::Double(vec![1.0]), -1.0));
+        assert!(!exceeds_deadband(&last, &Value::Double(vec![1.5]), 0.5));
+        assert!(exceeds_deadband(&last, &Value::Double(vec![0.4]), 0.5));
+        assert!(exceeds_deadband(&last, &Value::Double(vec![f64::NAN]), 10.0));
+        assert!(!exceeds_deadband(&Value::Double(vec![f64::NAN]), &Value::Double(vec![f64::NAN]), 0.0));
+
+        // Arrays and strings are posted on any change
+        assert!(exceeds_deadband(&Value::Long(vec![1, 2]), &Value::Long(vec![1, 3]), 5.0));
+        assert!(!exceeds_deadband(&Value::String(vec!["a".into()]), &Value::String(vec!["a".into()]), 0.0));
+    }
+}
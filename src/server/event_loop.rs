@@ -0,0 +1,193 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, UdpSocket};
+
+use crate::protocol::{Message, Origin};
+use super::{Beacons, Circuit, Error, Shared};
+
+use log::{debug, error, trace, warn};
+
+/// Token of the waker interrupting the loop when the server is stopped.
+pub(crate) const WAKER: Token = Token(usize::MAX - 1);
+/// Token of the socket receiving searches. Circuits use tokens derived from server IDs.
+const SEARCH: Token = Token(usize::MAX - 2);
+/// Token of the socket accepting circuits.
+const LISTENER: Token = Token(usize::MAX - 3);
+
+/// Largest UDP datagram the server sends or expects to receive.
+const MAX_UDP_SIZE: usize = 1472;
+
+/// Readiness-driven loop serving every socket of a server on one thread.
+pub(crate) struct EventLoop {
+    poll: Poll,
+    shared: Arc<Shared>,
+    search_socket: UdpSocket,
+    listener: TcpListener,
+    beacons: Beacons,
+}
+
+impl EventLoop {
+    /// Registers the search socket and the listener with `poll`.
+    pub(crate) fn new(poll: Poll, shared: Arc<Shared>, search_socket: std::net::UdpSocket, listener: std::net::TcpListener, beacons: Beacons) -> Result<Self, Error> {
+        search_socket.set_nonblocking(true)?;
+        listener.set_nonblocking(true)?;
+        let mut search_socket = UdpSocket::from_std(search_socket);
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry().register(&mut search_socket, SEARCH, Interest::READABLE)?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        Ok(Self { poll, shared, search_socket, listener, beacons })
+    }
+
+    /// Runs the loop on a new thread until the server stops it.
+    pub(crate) fn spawn(mut self) {
+        std::thread::spawn(move || {
+            self.run();
+            self.shutdown();
+        });
+    }
+
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(256);
+
+        while !self.shared.stopped.load(Ordering::SeqCst) {
+            let timeout = self.beacons.next_send().saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Event loop failed: {:?}", e);
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => (),
+                    SEARCH => self.receive_searches(),
+                    LISTENER => self.accept(),
+                    token => {
+                        let circuit = self.shared.circuits.lock().unwrap().get(&token).cloned();
+                        if let Some(circuit) = circuit {
+                            if !circuit.ready(event, &self.shared) {
+                                self.finish(&circuit);
+                            }
+                        }
+                    },
+                }
+            }
+
+            self.beacons.send(Instant::now());
+        }
+    }
+
+    /// Closes every circuit once the loop has been stopped.
+    fn shutdown(&mut self) {
+        debug!("Server event loop stopped");
+        for circuit in self.shared.open_circuits() {
+            self.finish(&circuit);
+        }
+    }
+
+    /// Deregisters an ended circuit, once.
+    fn finish(&self, circuit: &Arc<Circuit>) {
+        if self.shared.circuits.lock().unwrap().remove(&circuit.token()).is_some() {
+            circuit.finish();
+        }
+    }
+
+    /// Accepts every pending connection as a new circuit.
+    fn accept(&mut self) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Could not accept circuit: {:?}", e);
+                    return;
+                }
+            };
+
+            let token = Token(self.shared.next_id() as usize);
+            match Circuit::new(stream, peer, self.poll.registry(), token, &self.shared.config) {
+                Ok(circuit) => { self.shared.circuits.lock().unwrap().insert(token, circuit); },
+                Err(e) => warn!("Could not serve circuit from {}: {:?}", peer, e),
+            }
+        }
+    }
+
+    /// Answers every search received for a hosted variable.
+    fn receive_searches(&mut self) {
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        loop {
+            let (amt, src) = match self.search_socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Could not receive search: {:?}", e);
+                    return;
+                }
+            };
+            let messages = match Message::all_from_bytes(&buf[..amt], Origin::Client) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    debug!("Could not parse search from {}: {:?}", src, e);
+                    continue;
+                }
+            };
+
+            let mut replies = vec!();
+            for message in messages {
+                if let Message::Search { reply, cid, name, .. } = message {
+                    if self.shared.pv(&name).is_some() {
+                        trace!("Answering search for {} from {}", name, src);
+                        replies.push(Message::SearchResponse {
+                            port: self.shared.tcp_port,
+                            server_ip: self.server_ip(),
+                            cid,
+                            minor_version: crate::MINOR_PROTOCOL_VERSION,
+                        });
+                    } else if reply {
+                        replies.push(Message::NotFound { minor_version: crate::MINOR_PROTOCOL_VERSION, cid });
+                    }
+                }
+            }
+            if !replies.is_empty() {
+                self.send_replies(&replies, src);
+            }
+        }
+    }
+
+    /// Address announced in search replies: the configured interface, or 0xFFFFFFFF for the reply's source address.
+    fn server_ip(&self) -> u32 {
+        match self.shared.config.interface {
+            IpAddr::V4(ip) if !ip.is_unspecified() => u32::from(ip),
+            _ => u32::MAX,
+        }
+    }
+
+    /// Sends search replies to `address`, packed into as few datagrams as possible, each starting with CA_PROTO_VERSION.
+    fn send_replies(&mut self, replies: &[Message], address: SocketAddr) {
+        let version = Message::Version { priority: 0, minor_version: crate::MINOR_PROTOCOL_VERSION }.as_bytes();
+        let mut datagrams = vec!(version.clone());
+        for reply in replies {
+            let reply = reply.as_bytes();
+            let size = datagrams.last().map_or(0, Vec::len);
+            if size + reply.len() > MAX_UDP_SIZE && size > version.len() {
+                datagrams.push(version.clone());
+            }
+            datagrams.last_mut().unwrap().extend(reply);
+        }
+        for datagram in datagrams {
+            if let Err(e) = self.search_socket.send_to(&datagram, address) {
+                warn!("Could not send search reply to {}: {:?}", address, e);
+            }
+        }
+    }
+}
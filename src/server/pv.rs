@@ -0,0 +1,104 @@
+use std::sync::{Arc, Weak};
+
+use crate::dbr::{FieldType, Limits, Time, Value};
+use crate::protocol::eca;
+use super::{Error, Shared};
+
+/// Process variable hosted by a [`Server`](super::Server).
+///
+/// Hardware drivers, computed values and stored settings all plug into the server through this trait.
+/// The server calls into the variable from its event loop thread, so `read` and `write` should return promptly;
+/// slow writes hand their [`WriteCompletion`] to another thread instead of blocking.
+pub trait ProcessVariable: Send + Sync {
+    /// Type the value is stored in and announced to clients as.
+    fn native_type(&self) -> FieldType;
+
+    /// Number of elements of the value.
+    fn element_count(&self) -> usize;
+
+    /// Returns the current value with its alarm state and timestamp.
+    fn read(&self) -> Result<Time, Error>;
+
+    /// Starts writing `value`, already converted to the native type and holding at most `element_count` elements.
+    ///
+    /// The client waiting on a CA_PROTO_WRITE_NOTIFY is answered once `completion` is completed.
+    /// Changes are not posted to subscribers automatically; call [`ChangeNotifier::post`] once the value has changed.
+    fn write(&self, value: Value, completion: WriteCompletion);
+
+    /// Returns the properties sent with DBR_GR and DBR_CTRL values. Enum strings are also used to convert
+    /// enum values to and from DBR_STRING. The default implementation reports no properties.
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+
+    /// Receives the notifier for the server the variable was just added to. Variables whose value can change
+    /// keep it and post their changes through it; the default implementation ignores it.
+    fn attach(&self, _notifier: ChangeNotifier) {}
+}
+
+/// Display and control properties of a process variable.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub units: String,
+    pub precision: i16,
+    pub limits: Limits,
+    pub enum_strings: Vec<String>,
+}
+
+/// Callback receiving the outcome of a write.
+type WriteCallback = Box<dyn FnOnce(Result<(), Error>) + Send>;
+
+/// Handle finishing a write started by [`ProcessVariable::write`]. Dropping it without completing reports a failure.
+pub struct WriteCompletion {
+    callback: Option<WriteCallback>,
+}
+
+impl WriteCompletion {
+    pub(crate) fn new<F>(callback: F) -> Self
+    where F: FnOnce(Result<(), Error>) + Send + 'static {
+        Self { callback: Some(Box::new(callback)) }
+    }
+
+    /// Reports the outcome of the write. May be called from any thread.
+    pub fn complete(mut self, result: Result<(), Error>) {
+        if let Some(callback) = self.callback.take() {
+            callback(result);
+        }
+    }
+}
+
+impl Drop for WriteCompletion {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(Error::from_eca(eca::ECA_PUTFAIL)));
+        }
+    }
+}
+
+/// Handle through which a process variable reports changes to the server, which forwards them to subscribers.
+#[derive(Clone)]
+pub struct ChangeNotifier {
+    shared: Weak<Shared>,
+    pv: Weak<dyn ProcessVariable>,
+}
+
+impl ChangeNotifier {
+    pub(crate) fn new(shared: &Arc<Shared>, pv: &Arc<dyn ProcessVariable>) -> Self {
+        Self { shared: Arc::downgrade(shared), pv: Arc::downgrade(pv) }
+    }
+
+    /// Whether the server the notifier belongs to still exists.
+    pub(crate) fn is_alive(&self) -> bool {
+        self.shared.strong_count() > 0
+    }
+
+    /// Sends the current value to every subscription whose event mask shares a bit with `mask`,
+    /// a combination of the `protocol::DBE_*` bits describing the change.
+    ///
+    /// Reads the variable on the calling thread, so it must not be called while holding a lock `read` takes.
+    pub fn post(&self, mask: u16) {
+        if let (Some(shared), Some(pv)) = (self.shared.upgrade(), self.pv.upgrade()) {
+            shared.post(&pv, mask);
+        }
+    }
+}